@@ -1,141 +1,469 @@
-use std::io::{BufRead, Seek, Write};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
 
-use crate::error::AssemblyError;
-use crate::{instruction::Instruction, AsmError, Config, Result, SymbolTable};
+use crate::error::{AssemblyError, ErrorCode};
+use crate::output::OutputFormat;
+use crate::preprocessor::{Preprocessor, SourceLine};
+use crate::emulator::ROM_SIZE;
+use crate::instruction::{a_value, MAX_A_VALUE};
+use crate::lint::{self, LintLevels};
+use crate::optimizer;
+use crate::symbol_table::SCREEN;
+use crate::{instruction::Instruction, AsmError, Options, Result, SymbolKind, SymbolTable};
 
 pub struct Assembler<R: BufRead, W: Write> {
-    config: Config,
     reader: R,
     writer: W,
     symbol_table: SymbolTable,
     pub errors: Vec<AsmError>,
+    pub warnings: Vec<AsmError>,
+    options: Options,
+    format: OutputFormat,
+    words: Vec<u16>,
     current_address: u16,
-    line_number: usize,
+    file: Option<PathBuf>,
+    listing: Option<Vec<ListingEntry>>,
+    // Kept after assembly for the linter, along with the comment-only lines
+    // that may carry `// lint:` directives.
+    parsed_lines: Vec<ParsedLine>,
+    comments: Vec<SourceLine>,
+    words_saved: usize,
 }
 
-impl<R: BufRead + Seek, W: Write> Assembler<R, W> {
-    pub fn new(reader: R, writer: W, config: Config, symbol_table: SymbolTable) -> Self {
+// The result of a successful in-memory assembly.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub words: Vec<u16>,
+    pub symbol_table: SymbolTable,
+    pub warnings: Vec<AsmError>,
+}
+
+pub(crate) struct ParsedLine {
+    pub instruction: Instruction,
+    pub source: SourceLine,
+}
+
+// One row of a .lst file. Labels have no word but are listed at the address
+// they resolved to.
+struct ListingEntry {
+    address: u16,
+    word: Option<u16>,
+    line: usize,
+    source: String,
+}
+
+impl<R: BufRead, W: Write> Assembler<R, W> {
+    pub fn new(reader: R, writer: W, symbol_table: SymbolTable) -> Self {
         Assembler {
-            config,
             reader,
             writer,
             symbol_table,
             errors: Vec::new(),
+            warnings: Vec::new(),
+            options: Options::default(),
+            format: OutputFormat::Hack,
+            words: Vec::new(),
             current_address: 0,
-            line_number: 0,
+            file: None,
+            listing: None,
+            parsed_lines: Vec::new(),
+            comments: Vec::new(),
+            words_saved: 0,
         }
     }
 
-    pub fn assemble(&mut self) -> std::result::Result<(), AssemblyError> {
-        self.build_symbol_table()?;
-        self.reader.seek(std::io::SeekFrom::Start(0))?;
-        self.line_number = 1;
-        let reader_lines = self.reader.by_ref().lines();
-
-        let mut instructions: Vec<Instruction> = Vec::new();
-        for line_result in  reader_lines {
-            let line = match line_result {
-                Ok(line) => line,
-                Err(e) => {
-                    self.errors.push(AsmError::SyntaxError {
-                        line: self.line_number,
-                        message: format!("Failed to read line: {}", e),
-                    });
-                    continue;
-                }
-            };
+    pub fn symbol_table(&self) -> &SymbolTable {
+        &self.symbol_table
+    }
 
-            let sanitized = match sanitize_line(&line, self.line_number) {
-                Ok(opt) => opt,
-                Err(_) => continue,
-            };
+    // How many words the optimizer removed, if it ran.
+    pub fn words_saved(&self) -> usize {
+        self.words_saved
+    }
 
-            if let Some(sanitized_line) = sanitized {
-                match Instruction::parse(&sanitized_line, self.line_number) {
-                    Ok(instruction) => instructions.push(instruction),
-                    Err(e) => self.errors.push(e)
-                }
-            }
-            self.line_number += 1;
+    pub fn into_program(self) -> Program {
+        Program {
+            words: self.words,
+            symbol_table: self.symbol_table,
+            warnings: self.warnings,
         }
+    }
+
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn with_format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    // The path the source was read from, used to find files it includes.
+    pub fn with_file<P: Into<PathBuf>>(mut self, file: P) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn with_listing(mut self) -> Self {
+        self.listing = Some(Vec::new());
+        self
+    }
+
+    pub fn assemble(&mut self) -> std::result::Result<(), AssemblyError> {
+        let mut parsed_lines = self.parse_lines();
 
         if !self.errors.is_empty() {
             return Err(AssemblyError::AsmErrors(self.errors.clone()));
         }
 
-        for instruction in instructions {
-            self.handle_instruction(instruction)?;
+        self.build_symbol_table(&parsed_lines);
+        self.resolve_symbols(&parsed_lines);
+        self.check_label_uses(&parsed_lines);
+
+        if !self.errors.is_empty() {
+            return Err(AssemblyError::AsmErrors(self.errors.clone()));
         }
 
-        println!(
-            "Successfully assembled the file: {}",
-            self.config.output_file.display()
-        );
-        self.writer.flush()?;
+        if self.options.optimize {
+            self.optimize(&mut parsed_lines);
+        }
+
+        self.current_address = 0;
+        for parsed in &parsed_lines {
+            self.handle_instruction(&parsed.instruction, parsed.source.origin_line(), &parsed.source.text)?;
+        }
+        self.parsed_lines = parsed_lines;
+
+        self.format.write(&self.words, &mut self.writer)?;
 
         Ok(())
     }
-    fn handle_instruction(&mut self, instruction: Instruction) -> Result<()> {
-        match instruction {
-            Instruction::A(a_instruction) => {
-                let address = a_instruction.value();
-                writeln!(self.writer, "{:016b}", address)?;
-            }
-            Instruction::C(c_instruction) => {
-                let binary = c_instruction.to_binary();
-                writeln!(self.writer, "{}", binary)?;
+
+    // Reads and parses the whole input in one go, so the reader doesn't need to
+    // be seekable and stdin works as a source.
+    fn parse_lines(&mut self) -> Vec<ParsedLine> {
+        let mut lines = Vec::new();
+        for (index, line_result) in self.reader.by_ref().lines().enumerate() {
+            match line_result {
+                Ok(line) => lines.push((index + 1, line)),
+                Err(e) => self.errors.push(AsmError::error(
+                    ErrorCode::ReadFailure,
+                    index + 1,
+                    format!("failed to read line: {}", e),
+                )),
             }
-            Instruction::Variable(variable_name) => {
-                let address = self.symbol_table.add_variable(variable_name.clone())?;
-                let binary = format!("{:016b}", address);
-                writeln!(self.writer, "{}", binary)?;
+        }
+
+        let mut preprocessor = Preprocessor::new().with_include_paths(self.options.include_paths.clone());
+        let source_lines = preprocessor.process(self.file.as_deref(), lines);
+        self.errors.append(&mut preprocessor.errors);
+
+        let mut parsed_lines = Vec::new();
+        for source_line in source_lines {
+            let line = source_line.text.as_str();
+            match sanitize_line(line, source_line.line) {
+                Ok(Some(sanitized_line)) => {
+                    let offset = line.len() - line.trim_start().len();
+                    let mut warnings = Vec::new();
+                    let result =
+                        Instruction::parse_with(&sanitized_line, source_line.line, &self.options, &mut warnings);
+                    self.warnings.extend(
+                        warnings
+                            .into_iter()
+                            .map(|w| source_line.annotate(w.shifted(offset).with_source(line))),
+                    );
+                    match result {
+                        Ok(instruction) => parsed_lines.push(ParsedLine {
+                            instruction,
+                            source: source_line,
+                        }),
+                        Err(e) => self.errors.push(source_line.annotate(e.shifted(offset).with_source(line))),
+                    }
+                }
+                Ok(None) => {
+                    if source_line.text.contains("lint:") {
+                        self.comments.push(source_line);
+                    }
+                }
+                Err(e) => self.errors.push(source_line.annotate(e)),
             }
-            Instruction::L(_) => {}
+        }
+        parsed_lines
+    }
+
+    fn optimize(&mut self, parsed_lines: &mut Vec<ParsedLine>) {
+        if let Some((parsed, reason)) = optimizer::fixed_address(parsed_lines, &self.symbol_table) {
+            let text = parsed.source.text.trim();
+            self.warnings.push(
+                parsed
+                    .source
+                    .error(
+                        ErrorCode::NotOptimized,
+                        format!("`{}` {}, so the program isn't optimized", text, reason),
+                        text,
+                    )
+                    .into_warning()
+                    .with_note("removing instructions would move the code it points at"),
+            );
+            return;
+        }
+        self.words_saved = optimizer::optimize(parsed_lines, &mut self.symbol_table);
+    }
+
+    // The source line each word in ROM came from, for the debugger.
+    pub(crate) fn source_lines(&self) -> Vec<SourceLine> {
+        self.parsed_lines
+            .iter()
+            .filter(|parsed| parsed.instruction.occupies_rom())
+            .map(|parsed| parsed.source.clone())
+            .collect()
+    }
+
+    // The warnings from assembly that the linter doesn't also report:
+    // `@LABEL` followed by M is reported by its lint instead.
+    pub fn unlinted_warnings(&self) -> impl Iterator<Item = &AsmError> {
+        self.warnings.iter().filter(|warning| warning.code != ErrorCode::LabelAsVariable)
+    }
+
+    // Runs the linter over the assembled program.
+    pub fn lint(&self, levels: &LintLevels) -> Vec<AsmError> {
+        lint::check(&self.parsed_lines, &self.comments, &self.symbol_table, levels)
+    }
+
+    fn handle_instruction(&mut self, instruction: &Instruction, line: usize, source: &str) -> Result<()> {
+        let word = instruction.encode(&mut self.symbol_table, line)?;
+        if let Some(word) = word {
+            self.words.push(word);
+        }
+
+        if let Some(listing) = self.listing.as_mut() {
+            let address = match instruction {
+                Instruction::L(label) => *self
+                    .symbol_table
+                    .get_address(label)
+                    .ok_or_else(|| format!("Label {} not found", label))?,
+                _ => self.current_address,
+            };
+            listing.push(ListingEntry {
+                address,
+                word,
+                line,
+                source: source.trim_end().to_string(),
+            });
+        }
+
+        if word.is_some() {
+            self.current_address += 1;
         }
         Ok(())
     }
 
-    fn build_symbol_table(&mut self) -> Result<()> {
-        let reader_lines = self.reader.by_ref().lines();
+    pub fn write_listing<L: Write>(&self, mut writer: L) -> Result<()> {
+        let listing = self.listing.as_ref().ok_or("Listing was not enabled for this assembly")?;
+        writeln!(writer, " ROM  Binary            Hex   Line  Source")?;
+        for entry in listing {
+            match entry.word {
+                Some(word) => writeln!(
+                    writer,
+                    "{:04}  {:016b}  {:04X}  {:>4}  {}",
+                    entry.address, word, word, entry.line, entry.source
+                )?,
+                None => writeln!(
+                    writer,
+                    "{:04}  {:16}  {:4}  {:>4}  {}",
+                    entry.address, "", "", entry.line, entry.source
+                )?,
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
 
-        for line_result in reader_lines {
-            self.line_number += 1;
-            let line = line_result?;
+    fn build_symbol_table(&mut self, parsed_lines: &[ParsedLine]) {
+        let mut words = parsed_lines.iter().filter(|parsed| parsed.instruction.occupies_rom());
+        if let Some(first_overflow) = words.by_ref().nth(ROM_SIZE) {
+            let total = ROM_SIZE + 1 + words.count();
+            self.errors.push(
+                first_overflow
+                    .source
+                    .error(
+                        ErrorCode::ProgramTooLarge,
+                        format!("the program is {} words long but the ROM only holds {}", total, ROM_SIZE),
+                        first_overflow.source.text.trim(),
+                    )
+                    .with_note("this is the first instruction that doesn't fit"),
+            );
+            return;
+        }
 
-            let sanitized_result = sanitize_line(&line, self.line_number);
-            let sanitized = match sanitized_result {
-                Ok(s) => s,
-                Err(e) => {
-                    self.errors.push(e);
+        self.current_address = 0;
+        // Where each label and constant was first defined, for redefinition errors.
+        let mut definitions: HashMap<&str, &SourceLine> = HashMap::new();
+        for parsed in parsed_lines {
+            let name = match &parsed.instruction {
+                Instruction::L(label) | Instruction::Constant(label, _) => label,
+                _ => {
+                    self.current_address += 1;
                     continue;
                 }
             };
+            match (self.symbol_table.get(name).map(|symbol| symbol.kind), &parsed.instruction) {
+                (None, Instruction::Constant(_, value)) => {
+                    self.symbol_table.add_constant(name.clone(), *value);
+                    definitions.insert(name, &parsed.source);
+                }
+                (None, _) => {
+                    self.symbol_table.add_label(name.clone(), self.current_address);
+                    definitions.insert(name, &parsed.source);
+                }
+                (Some(SymbolKind::Predefined), Instruction::Constant(..)) => {
+                    self.errors.push(parsed.source.error(
+                        ErrorCode::DuplicateSymbol,
+                        format!("`{}` is a predefined symbol and can't be redefined", name),
+                        name,
+                    ));
+                }
+                (Some(SymbolKind::Predefined), _) => {
+                    let address = self.symbol_table.get_address(name).copied().unwrap_or_default();
+                    self.warnings.push(
+                        parsed
+                            .source
+                            .error(
+                                ErrorCode::ShadowedSymbol,
+                                format!("label `{}` has the same name as a predefined symbol", name),
+                                name,
+                            )
+                            .into_warning()
+                            .with_hint(format!("`@{}` still loads {}; rename the label", name, address)),
+                    );
+                }
+                (Some(previous), _) => {
+                    self.errors.push(redefinition(&parsed.source, name, previous, definitions[name.as_str()]));
+                }
+            }
+        }
+    }
 
-            if let Some(ref line) = sanitized {
-                let instruction_result = Instruction::parse(line, self.line_number);
-                if let Ok(instruction) = instruction_result {
-                    if let Instruction::L(label) = instruction {
-                        self.symbol_table.add_label(label, self.current_address);
-                    } else {
-                        self.current_address += 1;
+    // Checks that each `@expression` evaluates to a value that fits and
+    // allocates variables in order of first use. This runs after every label
+    // and constant is known, so expressions can refer forward.
+    fn resolve_symbols(&mut self, parsed_lines: &[ParsedLine]) {
+        let mut out_of_ram = false;
+        for parsed in parsed_lines {
+            let line = parsed.source.line;
+            let text = parsed.source.text.as_str();
+            let offset = text.len() - text.trim_start().len();
+            let result = match &parsed.instruction {
+                Instruction::Expression(expr) => match expr.evaluate(&self.symbol_table, line) {
+                    Ok(value) => a_value(value, &expr.span, line).map(|_| ()),
+                    Err(e) => Err(e),
+                },
+                Instruction::Variable(name) => match self.symbol_table.get(name).copied() {
+                    Some(symbol) if symbol.address > MAX_A_VALUE => Err(AsmError::error(
+                        ErrorCode::ValueOutOfRange,
+                        line,
+                        format!("{} `{}` is at address {}, which doesn't fit in an A-instruction", symbol.kind, name, symbol.address),
+                    )
+                    .with_span(1..1 + name.len())
+                    .with_hint(format!("A-instructions can only load values from 0 to {}", MAX_A_VALUE))),
+                    Some(_) => Ok(()),
+                    None if out_of_ram => Ok(()),
+                    None if self.symbol_table.next_variable_address() >= SCREEN => {
+                        out_of_ram = true;
+                        Err(AsmError::error(
+                            ErrorCode::ValueOutOfRange,
+                            line,
+                            format!("no RAM left for variable `{}`", name),
+                        )
+                        .with_span(1..1 + name.len())
+                        .with_hint(format!(
+                            "variables are allocated from RAM[16] up to RAM[{}]; the screen starts at {}",
+                            SCREEN - 1,
+                            SCREEN
+                        )))
                     }
-                }
+                    None => self.symbol_table.add_variable(name.clone()).map(|_| ()).map_err(|e| {
+                        AsmError::error(ErrorCode::Internal, line, e.to_string())
+                    }),
+                },
+                _ => Ok(()),
+            };
+            if let Err(e) = result {
+                self.errors.push(parsed.source.annotate(e.shifted(offset).with_source(text)));
             }
         }
-        Ok(())
     }
+
+    // Warns about `@LABEL` followed by an instruction that reads or writes M,
+    // which treats the label's ROM address as a RAM variable.
+    fn check_label_uses(&mut self, parsed_lines: &[ParsedLine]) {
+        let instructions: Vec<&ParsedLine> = parsed_lines
+            .iter()
+            .filter(|parsed| parsed.instruction.occupies_rom())
+            .collect();
+        for pair in instructions.windows(2) {
+            let (Instruction::Variable(name), Instruction::C(next)) = (&pair[0].instruction, &pair[1].instruction) else {
+                continue;
+            };
+            let Some(symbol) = self.symbol_table.get(name) else {
+                continue;
+            };
+            if symbol.kind != SymbolKind::Label || !next.uses_memory() {
+                continue;
+            }
+            let address = symbol.address;
+            self.warnings.push(
+                pair[0]
+                    .source
+                    .error(
+                        ErrorCode::LabelAsVariable,
+                        format!("label `{}` is used as a variable", name),
+                        name,
+                    )
+                    .into_warning()
+                    .with_note(format!(
+                        "`{}` on the next line accesses RAM[{}], the label's ROM address",
+                        next, address
+                    ))
+                    .with_hint("use a different name for the variable or the label"),
+            );
+        }
+    }
+}
+
+// An error for `name` on `source` clashing with an earlier `kind` of the same
+// name defined on `definition`.
+fn redefinition(source: &SourceLine, name: &str, kind: SymbolKind, definition: &SourceLine) -> AsmError {
+    let location = match &definition.file {
+        Some(file) => format!("{}:{}", file.display(), definition.line),
+        None => format!("line {}", definition.line),
+    };
+    source
+        .error(
+            ErrorCode::DuplicateSymbol,
+            format!("`{}` is already defined as a {}", name, kind),
+            name,
+        )
+        .with_note(format!("the {} is defined at {}", kind, location))
 }
 
-fn sanitize_line(line: &str, line_number: usize) -> std::result::Result<Option<String>, AsmError> {
+#[allow(clippy::result_large_err)]
+pub(crate) fn sanitize_line(line: &str, line_number: usize) -> std::result::Result<Option<String>, AsmError> {
     let trimmed = line.trim();
     if trimmed.is_empty() {
         return Ok(None);
     }
-    if !trimmed.is_ascii() {
-        return Err(AsmError::SyntaxError {
-            line: line_number,
-            message: "Non-ASCII characters are not allowed".to_string(),
-        });
+    if let Some(position) = line.find(|c: char| !c.is_ascii()) {
+        let width = line[position..].chars().next().map_or(1, char::len_utf8);
+        return Err(AsmError::error(
+            ErrorCode::NonAscii,
+            line_number,
+            "non-ASCII characters are not allowed",
+        )
+        .with_span(position..position + width)
+        .with_source(line));
     }
 
     if trimmed.starts_with("//") {
@@ -148,3 +476,88 @@ fn sanitize_line(line: &str, line_number: usize) -> std::result::Result<Option<S
 
     Ok(Some(trimmed.to_string()))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_listing() {
+        let source = "// counter\n(START)\n  @i  // i\n\n  M=0\n(END)\n  @END\n  0;JMP\n";
+        let mut assembler =
+            Assembler::new(Cursor::new(source), Vec::new(), SymbolTable::default()).with_listing();
+        assembler.assemble().unwrap();
+
+        let mut listing = Vec::new();
+        assembler.write_listing(&mut listing).unwrap();
+        assert_eq!(
+            String::from_utf8(listing).unwrap(),
+            [
+                " ROM  Binary            Hex   Line  Source",
+                "0000                             2  (START)",
+                "0000  0000000000010000  0010     3    @i  // i",
+                "0001  1110101010001000  EA88     5    M=0",
+                "0002                             6  (END)",
+                "0002  0000000000000010  0002     7    @END",
+                "0003  1110101010000111  EA87     8    0;JMP",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn test_constants() {
+        let source = "@WIDTH\n.equ WIDTH 32\nD=A\n";
+        let mut assembler = Assembler::new(Cursor::new(source), Vec::new(), SymbolTable::default());
+        assembler.assemble().unwrap();
+        let program = assembler.into_program();
+        assert_eq!(program.words, [32, 0b1110110000010000]);
+        assert_eq!(program.symbol_table.get("WIDTH").unwrap().kind, SymbolKind::Constant);
+
+        let errors = assemble_errors("(LOOP)\n.equ LOOP 1\n.equ KBD 1\n");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "`LOOP` is already defined as a label");
+        assert_eq!(errors[0].notes, ["the label is defined at line 1"]);
+        assert_eq!(errors[1].code, ErrorCode::DuplicateSymbol);
+    }
+
+    fn assemble_errors(source: &str) -> Vec<AsmError> {
+        let mut assembler = Assembler::new(Cursor::new(source), Vec::new(), SymbolTable::default());
+        match assembler.assemble() {
+            Err(AssemblyError::AsmErrors(errors)) => errors,
+            _ => panic!("assembly should fail"),
+        }
+    }
+
+    #[test]
+    fn test_memory_limits() {
+        let too_long = "D=D+1\n".repeat(ROM_SIZE) + "(END)\n@END\n0;JMP\n";
+        let errors = assemble_errors(&too_long);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, ErrorCode::ProgramTooLarge);
+        assert_eq!(errors[0].line, ROM_SIZE + 2);
+        assert_eq!(errors[0].message, "the program is 32770 words long but the ROM only holds 32768");
+
+        let variables: String = (0..=SCREEN - 16).map(|i| format!("@v{}\n", i)).collect();
+        let errors = assemble_errors(&variables);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, format!("no RAM left for variable `v{}`", SCREEN - 16));
+    }
+
+    #[test]
+    fn test_label_diagnostics() {
+        let errors = assemble_errors("(LOOP)\n@LOOP\n0;JMP\n(LOOP)\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 4);
+        assert_eq!(errors[0].notes, ["the label is defined at line 1"]);
+
+        let source = "(R3)\n@R3\nD=A\n@i\nM=D\n(i)\n@i\n0;JMP\n";
+        let mut assembler = Assembler::new(Cursor::new(source), Vec::new(), SymbolTable::default());
+        assembler.assemble().unwrap();
+        let codes: Vec<(ErrorCode, usize)> = assembler.warnings.iter().map(|w| (w.code, w.line)).collect();
+        assert_eq!(codes, [(ErrorCode::ShadowedSymbol, 1), (ErrorCode::LabelAsVariable, 4)]);
+        assert_eq!(assembler.into_program().words[0], 3);
+    }
+}
@@ -0,0 +1,15 @@
+use hack_assembler::LanguageServer;
+
+// Speaks the Language Server Protocol over stdio, for editors such as
+// VS Code and Neovim.
+fn main() {
+    let mut server = LanguageServer::new();
+    if let Err(err) = server.serve(std::io::stdin().lock(), std::io::stdout().lock()) {
+        eprintln!("Error running the Hack language server: {}", err);
+        std::process::exit(1);
+    }
+    // The protocol asks for a failing exit code if the client didn't shut the server down first.
+    if !server.is_shut_down() {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,620 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use crate::assembler::Assembler;
+use crate::emulator::RAM_SIZE;
+use crate::error::AssemblyError;
+use crate::instruction::CInstruction;
+use crate::preprocessor::SourceLine;
+use crate::suggest;
+use crate::{Emulator, Halt, Options, SymbolKind, SymbolTable};
+
+// A gdb-like debugger for Hack programs. Commands are read a line at a time,
+// so the same code drives the interactive `debug` subcommand and the tests.
+
+const COMMANDS: [&str; 14] = [
+    "break", "watch", "delete", "info", "step", "next", "continue", "print", "x", "set", "disassemble", "reset",
+    "help", "quit",
+];
+
+const HELP: &str = "\
+break, b LOCATION       Stops at a label, a source line, FILE:LINE or *ADDRESS in ROM
+watch, w ADDRESS        Stops when a RAM address or variable changes
+delete, d [N]           Deletes breakpoint or watchpoint N, or all of them
+info, i breakpoints     Lists breakpoints and watchpoints
+info, i registers       Shows A, D and PC
+step, s [N]             Executes N instructions
+next, n [N]             Executes until the program reaches another source line, N times
+continue, c             Runs until a breakpoint or watchpoint is hit or the program stops
+print, p WHAT           Shows A, D, PC, M, a symbol or a RAM address
+x ADDRESS [COUNT]       Shows COUNT words of RAM starting at ADDRESS
+set WHAT VALUE          Sets A, D, PC, M, a variable or a RAM address
+disassemble, disas [N]  Shows N instructions around PC
+reset                   Restarts the program, keeping breakpoints and watchpoints
+help, h                 Shows this list
+quit, q                 Exits the debugger
+An empty line repeats the last command.";
+
+enum Stop {
+    Breakpoint(u16),
+    // The value is the one last seen, so changes can be reported.
+    Watchpoint { address: u16, value: u16 },
+}
+
+struct Point {
+    number: usize,
+    stop: Stop,
+    // What it was set on, as typed.
+    name: String,
+}
+
+pub struct Debugger {
+    emulator: Emulator,
+    symbol_table: SymbolTable,
+    // The source of each word in ROM; empty for .hack programs.
+    sources: Vec<SourceLine>,
+    points: Vec<Point>,
+    next_number: usize,
+    // How many instructions a single `continue`, `step` or `next` may run.
+    max_steps: usize,
+}
+
+impl Debugger {
+    pub fn new(words: Vec<u16>, symbol_table: SymbolTable) -> Self {
+        Debugger {
+            emulator: Emulator::new(words),
+            symbol_table,
+            sources: Vec::new(),
+            points: Vec::new(),
+            next_number: 1,
+            max_steps: 1_000_000,
+        }
+    }
+
+    // Assembles a program, keeping the line each instruction came from.
+    pub fn assemble<R: BufRead>(
+        reader: R,
+        file: Option<&Path>,
+        options: Options,
+    ) -> std::result::Result<Self, AssemblyError> {
+        let mut assembler = Assembler::new(reader, io::sink(), SymbolTable::default()).with_options(options);
+        if let Some(file) = file {
+            assembler = assembler.with_file(file);
+        }
+        assembler.assemble()?;
+        let sources = assembler.source_lines();
+        let program = assembler.into_program();
+        let mut debugger = Debugger::new(program.words, program.symbol_table);
+        debugger.sources = sources;
+        Ok(debugger)
+    }
+
+    // Loads a .asm or .hack file. Without the source, breakpoints can only
+    // be set on ROM addresses.
+    pub fn load(path: &Path, options: Options) -> std::result::Result<Self, AssemblyError> {
+        let reader = BufReader::new(File::open(path)?);
+        if path.extension().unwrap_or_default() == "asm" {
+            return Debugger::assemble(reader, Some(path), options);
+        }
+        let emulator = Emulator::from_hack(reader)?;
+        Ok(Debugger::new(emulator.rom().to_vec(), SymbolTable::default()))
+    }
+
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    pub fn emulator(&self) -> &Emulator {
+        &self.emulator
+    }
+
+    // Reads commands until `quit` or the end of the input.
+    pub fn repl<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        let mut last = String::new();
+        loop {
+            write!(output, "(hdb) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+            // An empty line repeats the last command, as in gdb.
+            let command = match line.trim() {
+                "" => last.clone(),
+                command => command.to_string(),
+            };
+            if !self.execute(&command, &mut output)? {
+                return Ok(());
+            }
+            last = command;
+        }
+    }
+
+    // Runs one command and prints what it reports. Returns false for `quit`.
+    pub fn execute<W: Write>(&mut self, command: &str, output: &mut W) -> io::Result<bool> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let Some((&name, arguments)) = words.split_first() else {
+            return Ok(true);
+        };
+        let result = match name {
+            "break" | "b" => self.add_breakpoint(arguments),
+            "watch" | "w" => self.add_watchpoint(arguments),
+            "delete" | "d" => self.delete(arguments),
+            "info" | "i" => self.info(arguments),
+            "step" | "s" => count(arguments).map(|mut remaining| {
+                self.resume(|_| {
+                    remaining -= 1;
+                    remaining == 0
+                })
+            }),
+            "next" | "n" => count(arguments).map(|mut remaining| {
+                let mut line = self.line(self.emulator.pc());
+                // Without the source, every instruction is its own line.
+                self.resume(|debugger| {
+                    let current = debugger.line(debugger.emulator.pc());
+                    if current != line || current.is_none() {
+                        line = current;
+                        remaining -= 1;
+                    }
+                    remaining == 0
+                })
+            }),
+            "continue" | "c" => Ok(self.resume(|_| false)),
+            "print" | "p" => self.print(arguments),
+            "x" => self.examine(arguments),
+            "set" => self.set(arguments),
+            "disassemble" | "disas" => self.disassemble(arguments),
+            "reset" => {
+                self.emulator = Emulator::new(self.emulator.rom().to_vec());
+                self.watch_changes();
+                Ok(self.location(0))
+            }
+            "help" | "h" => Ok(HELP.to_string()),
+            "quit" | "q" => return Ok(false),
+            _ => Err(match suggest::closest(name, COMMANDS) {
+                Some(command) => format!("unknown command `{}`; did you mean `{}`?", name, command),
+                None => format!("unknown command `{}`; try `help`", name),
+            }),
+        };
+        match result {
+            Ok(text) if text.is_empty() => {}
+            Ok(text) | Err(text) => writeln!(output, "{}", text)?,
+        }
+        Ok(true)
+    }
+
+    // Runs until `done` says to stop, a breakpoint or watchpoint is hit or
+    // the program halts. `done` is checked after each instruction.
+    fn resume<F: FnMut(&Debugger) -> bool>(&mut self, mut done: F) -> String {
+        for _ in 0..self.max_steps {
+            if let Some(halt) = self.emulator.step() {
+                return format!("Program stopped: {}\n{}", halt, self.location(self.emulator.pc()));
+            }
+            let pc = self.emulator.pc();
+            let changes = self.watch_changes();
+            if !changes.is_empty() {
+                return format!("{}\n{}", changes.join("\n"), self.location(pc));
+            }
+            let breakpoint = self
+                .points
+                .iter()
+                .find(|point| matches!(point.stop, Stop::Breakpoint(address) if address == pc));
+            if let Some(point) = breakpoint {
+                return format!("Breakpoint {}, {}", point.number, self.location(pc));
+            }
+            if done(self) {
+                return self.location(pc);
+            }
+        }
+        format!("Stopped: {}\n{}", Halt::StepLimit, self.location(self.emulator.pc()))
+    }
+
+    // Updates the value each watchpoint last saw, describing the ones that
+    // changed.
+    fn watch_changes(&mut self) -> Vec<String> {
+        let mut changes = Vec::new();
+        for point in &mut self.points {
+            if let Stop::Watchpoint { address, value } = &mut point.stop {
+                let new = self.emulator.peek(*address);
+                if new != *value {
+                    changes.push(format!(
+                        "Watchpoint {}: {} changed from {} to {}",
+                        point.number, point.name, *value as i16, new as i16
+                    ));
+                    *value = new;
+                }
+            }
+        }
+        changes
+    }
+
+    fn add_breakpoint(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let [location] = arguments else {
+            return Err(usage("break LABEL|LINE|FILE:LINE|*ADDRESS"));
+        };
+        let address = if let Some(address) = location.strip_prefix('*') {
+            let address = address.parse::<u16>().map_err(|_| format!("invalid ROM address `{}`", address))?;
+            if address as usize >= self.emulator.rom().len() {
+                return Err(format!("ROM[{}] is past the end of the program", address));
+            }
+            address
+        } else if let Some((file, line)) = location.rsplit_once(':') {
+            let line = line.parse::<usize>().map_err(|_| format!("invalid line number `{}`", line))?;
+            self.line_address(Some(file), line)?
+        } else if let Ok(line) = location.parse::<usize>() {
+            self.line_address(None, line)?
+        } else {
+            match self.symbol_table.get(location) {
+                Some(symbol) if symbol.kind == SymbolKind::Label => symbol.address,
+                Some(symbol) if symbol.kind == SymbolKind::Predefined => {
+                    return Err(format!("`{}` is a predefined symbol, not a label", location))
+                }
+                Some(symbol) => return Err(format!("`{}` is a {}, not a label", location, symbol.kind)),
+                None => return Err(self.unknown_symbol(location)),
+            }
+        };
+        let number = self.add(Stop::Breakpoint(address), location);
+        Ok(format!("Breakpoint {} at {}", number, self.location(address)))
+    }
+
+    // The first instruction on or after `line`, either in the file being
+    // debugged or in `file`, which may be included or define macros.
+    fn line_address(&self, file: Option<&str>, line: usize) -> Result<u16, String> {
+        if self.sources.is_empty() {
+            return Err("the program has no source lines; use *ADDRESS to break at a ROM address".to_string());
+        }
+        let position = self.sources.iter().position(|source| match file {
+            None => source.origin_line() >= line,
+            Some(file) => std::iter::once((&source.file, source.line))
+                .chain(source.expansions.iter().map(|expansion| (&expansion.file, expansion.line)))
+                .any(|(path, at)| path.as_ref().is_some_and(|path| path.ends_with(file)) && at >= line),
+        });
+        match position {
+            Some(address) => Ok(address as u16),
+            None => Err(format!("no instructions on or after line {}", line)),
+        }
+    }
+
+    fn add_watchpoint(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let [target] = arguments else {
+            return Err(usage("watch VARIABLE|ADDRESS"));
+        };
+        let address = self.ram_address(target)?;
+        let value = self.emulator.peek(address);
+        let number = self.add(Stop::Watchpoint { address, value }, target);
+        Ok(format!("Watchpoint {}: {} (RAM[{}] = {})", number, target, address, value as i16))
+    }
+
+    fn add(&mut self, stop: Stop, name: &str) -> usize {
+        let number = self.next_number;
+        self.next_number += 1;
+        self.points.push(Point {
+            number,
+            stop,
+            name: name.to_string(),
+        });
+        number
+    }
+
+    fn delete(&mut self, arguments: &[&str]) -> Result<String, String> {
+        match arguments {
+            [] => self.points.clear(),
+            [number] => {
+                let number = number.parse::<usize>().map_err(|_| usage("delete [N]"))?;
+                let index = self
+                    .points
+                    .iter()
+                    .position(|point| point.number == number)
+                    .ok_or_else(|| format!("no breakpoint or watchpoint number {}", number))?;
+                self.points.remove(index);
+            }
+            _ => return Err(usage("delete [N]")),
+        }
+        Ok(String::new())
+    }
+
+    fn info(&self, arguments: &[&str]) -> Result<String, String> {
+        match arguments {
+            ["breakpoints" | "b" | "watchpoints" | "w"] => {
+                if self.points.is_empty() {
+                    return Ok("No breakpoints or watchpoints".to_string());
+                }
+                let lines: Vec<String> = self
+                    .points
+                    .iter()
+                    .map(|point| match point.stop {
+                        Stop::Breakpoint(address) => {
+                            format!("{:<3} breakpoint  {} at PC {}", point.number, point.name, address)
+                        }
+                        Stop::Watchpoint { address, .. } => {
+                            format!("{:<3} watchpoint  {} at RAM[{}]", point.number, point.name, address)
+                        }
+                    })
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+            ["registers" | "r"] => Ok(format!(
+                "{}  steps: {}",
+                self.emulator.dump(0..0).trim_end(),
+                self.emulator.steps()
+            )),
+            _ => Err(usage("info breakpoints|registers")),
+        }
+    }
+
+    fn print(&self, arguments: &[&str]) -> Result<String, String> {
+        let [target] = arguments else {
+            return Err(usage("print A|D|PC|M|SYMBOL|ADDRESS"));
+        };
+        let emulator = &self.emulator;
+        let text = match *target {
+            "A" => format!("A = {}", emulator.a() as i16),
+            "D" => format!("D = {}", emulator.d() as i16),
+            "PC" => format!("PC = {}", emulator.pc()),
+            "M" => format!("M = RAM[{}] = {}", emulator.a(), emulator.peek(emulator.a()) as i16),
+            name => match self.symbol_table.get(name) {
+                Some(symbol) if symbol.kind == SymbolKind::Label => format!("{} = ROM[{}]", name, symbol.address),
+                Some(symbol) if symbol.kind == SymbolKind::Constant => format!("{} = {}", name, symbol.address),
+                _ => {
+                    let address = self.ram_address(name)?;
+                    format!("{} = RAM[{}] = {}", name, address, emulator.peek(address) as i16)
+                }
+            },
+        };
+        Ok(text)
+    }
+
+    fn examine(&self, arguments: &[&str]) -> Result<String, String> {
+        let (start, count) = match arguments {
+            [start] => (self.ram_address(start)?, 1),
+            [start, count] => (
+                self.ram_address(start)?,
+                count.parse::<usize>().map_err(|_| format!("invalid count `{}`", count))?,
+            ),
+            _ => return Err(usage("x ADDRESS [COUNT]")),
+        };
+        let end = (start as usize + count).min(RAM_SIZE);
+        let lines: Vec<String> = (start as usize..end)
+            .map(|address| format!("RAM[{}] = {}", address, self.emulator.peek(address as u16) as i16))
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    fn set(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let [target, value] = arguments else {
+            return Err(usage("set A|D|PC|M|VARIABLE|ADDRESS VALUE"));
+        };
+        let value = self.value(value)?;
+        match *target {
+            "A" => self.emulator.set_a(value),
+            "D" => self.emulator.set_d(value),
+            "PC" => self.emulator.set_pc(value),
+            "M" => self.emulator.poke(self.emulator.a(), value),
+            target => {
+                let address = self.ram_address(target)?;
+                self.emulator.poke(address, value);
+            }
+        }
+        // A change made by hand isn't one the program made.
+        self.watch_changes();
+        Ok(String::new())
+    }
+
+    // Shows `count` instructions around PC, marking PC with `=>` and
+    // breakpoints with `*`, with the labels that point at them.
+    fn disassemble(&self, arguments: &[&str]) -> Result<String, String> {
+        let count = match arguments {
+            [] => 10,
+            [count] => count.parse::<usize>().map_err(|_| format!("invalid count `{}`", count))?,
+            _ => return Err(usage("disassemble [N]")),
+        };
+        let pc = self.emulator.pc() as usize;
+        let start = pc.saturating_sub(count / 2);
+        let end = (start + count).min(self.emulator.rom().len());
+        let mut lines = Vec::new();
+        for address in start..end {
+            for (name, symbol) in self.symbol_table.iter() {
+                if symbol.kind == SymbolKind::Label && symbol.address as usize == address {
+                    lines.push(format!("         ({})", name));
+                }
+            }
+            let is_breakpoint = self
+                .points
+                .iter()
+                .any(|point| matches!(point.stop, Stop::Breakpoint(at) if at as usize == address));
+            let mut line = format!(
+                "{}{} {:>5}  {:<14}",
+                if address == pc { "=>" } else { "  " },
+                if is_breakpoint { "*" } else { " " },
+                address,
+                instruction(self.emulator.rom()[address])
+            );
+            if let Some(source) = self.sources.get(address) {
+                line.push_str(&format!("line {}: {}", source.origin_line(), source.code()));
+            }
+            lines.push(line.trim_end().to_string());
+        }
+        if lines.is_empty() {
+            return Ok(format!("PC {} is past the end of the program", pc));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    // Where the program is, as `PC 4, line 12: D=M`.
+    fn location(&self, address: u16) -> String {
+        match (self.sources.get(address as usize), self.emulator.rom().get(address as usize)) {
+            (Some(source), _) => format!("PC {}, line {}: {}", address, source.origin_line(), source.code()),
+            (None, Some(&word)) => format!("PC {}: {}", address, instruction(word)),
+            (None, None) => format!("PC {}: past the end of the program", address),
+        }
+    }
+
+    fn line(&self, address: u16) -> Option<usize> {
+        self.sources.get(address as usize).map(SourceLine::origin_line)
+    }
+
+    // A RAM address given as a number or a variable, which includes
+    // predefined symbols such as `R0` and `SCREEN`.
+    fn ram_address(&self, text: &str) -> Result<u16, String> {
+        if let Ok(address) = text.parse::<usize>() {
+            return match address < RAM_SIZE {
+                true => Ok(address as u16),
+                false => Err(format!("RAM addresses go up to {}", RAM_SIZE - 1)),
+            };
+        }
+        match self.symbol_table.get(text) {
+            Some(symbol) if matches!(symbol.kind, SymbolKind::Label | SymbolKind::Constant) => {
+                Err(format!("`{}` is a {}, not a RAM address", text, symbol.kind))
+            }
+            Some(symbol) => Ok(symbol.address),
+            None => Err(self.unknown_symbol(text)),
+        }
+    }
+
+    // A number from -32768 to 65535, or the value of a symbol.
+    fn value(&self, text: &str) -> Result<u16, String> {
+        if let Ok(value) = text.parse::<i32>() {
+            return match (i16::MIN as i32..=u16::MAX as i32).contains(&value) {
+                true => Ok(value as u16),
+                false => Err(format!("{} doesn't fit in 16 bits", value)),
+            };
+        }
+        match self.symbol_table.get(text) {
+            Some(symbol) => Ok(symbol.address),
+            None => Err(self.unknown_symbol(text)),
+        }
+    }
+
+    fn unknown_symbol(&self, name: &str) -> String {
+        match suggest::closest(name, self.symbol_table.iter().map(|(name, _)| name)) {
+            Some(symbol) => format!("no symbol `{}`; did you mean `{}`?", name, symbol),
+            None => format!("no symbol `{}`", name),
+        }
+    }
+}
+
+// The optional count after `step` and `next`.
+fn count(arguments: &[&str]) -> Result<usize, String> {
+    match arguments {
+        [] => Ok(1),
+        [count] => match count.parse::<usize>() {
+            Ok(count) if count > 0 => Ok(count),
+            _ => Err(format!("invalid count `{}`", count)),
+        },
+        _ => Err(usage("step|next [N]")),
+    }
+}
+
+fn usage(text: &str) -> String {
+    format!("usage: {}", text)
+}
+
+fn instruction(word: u16) -> String {
+    if word & 0x8000 == 0 {
+        return format!("@{}", word);
+    }
+    CInstruction::decode(word).map_or_else(|| format!("{:016b}", word), |c_instruction| c_instruction.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    const SOURCE: &str = "\
+// Counts i up to 3
+    @i
+    M=0
+(LOOP)
+    @i
+    M=M+1
+    D=M
+    @3
+    D=D-A
+    @LOOP
+    D;JLT
+(END)
+    @END
+    0;JMP
+";
+
+    fn session(commands: &str) -> String {
+        let mut debugger = Debugger::assemble(Cursor::new(SOURCE), None, Options::default()).unwrap();
+        let mut output = Vec::new();
+        debugger.repl(Cursor::new(commands), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    // The replies to each command, without prompts.
+    fn replies(output: &str) -> Vec<&str> {
+        output.split("(hdb) ").skip(1).map(str::trim_end).collect()
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let output = session("break LOOP\nbreak 7\nc\nc\n\nprint i\ndelete 1\ninfo b\nc\nprint D\nquit\n");
+        assert_eq!(
+            replies(&output),
+            [
+                "Breakpoint 1 at PC 2, line 5: @i",
+                "Breakpoint 2 at PC 4, line 7: D=M",
+                "Breakpoint 1, PC 2, line 5: @i",
+                "Breakpoint 2, PC 4, line 7: D=M",
+                "Breakpoint 1, PC 2, line 5: @i",
+                "i = RAM[16] = 1",
+                "",
+                "2   breakpoint  7 at PC 4",
+                "Breakpoint 2, PC 4, line 7: D=M",
+                "D = -2",
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stepping() {
+        let output = session("s 2\nn\nwatch i\nc\nset i 5\nc\nx R0 2\ninfo r\ndisas 4\n");
+        assert_eq!(
+            replies(&output),
+            [
+                "PC 2, line 5: @i",
+                "PC 3, line 6: M=M+1",
+                "Watchpoint 1: i (RAM[16] = 0)",
+                "Watchpoint 1: i changed from 0 to 1\nPC 4, line 7: D=M",
+                "",
+                "Program stopped: infinite loop at PC 9\nPC 9, line 13: @END",
+                "RAM[0] = 0\nRAM[1] = 0",
+                "A: 9  D: 2  PC: 9  steps: 11",
+                concat!(
+                    "        7  @2            line 10: @LOOP\n",
+                    "        8  D;JLT         line 11: D;JLT\n",
+                    "         (END)\n",
+                    "=>      9  @9            line 13: @END\n",
+                    "       10  0;JMP         line 14: 0;JMP",
+                ),
+                "",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let output = session("stpe\nbreak i\nbreak R0\nprint j\nbreak 99\nwatch LOOP\nset D 70000\n");
+        assert_eq!(
+            replies(&output),
+            [
+                "unknown command `stpe`; did you mean `step`?",
+                "`i` is a variable, not a label",
+                "`R0` is a predefined symbol, not a label",
+                "no symbol `j`; did you mean `i`?",
+                "no instructions on or after line 99",
+                "`LOOP` is a label, not a RAM address",
+                "70000 doesn't fit in 16 bits",
+                "",
+            ]
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+use crate::error::{AssemblyError, ErrorCode};
+use crate::instruction::{AInstruction, CInstruction, Instruction, Jump};
+use crate::AsmError;
+
+pub struct Disassembler<R: BufRead, W: Write> {
+    reader: R,
+    writer: W,
+    pub errors: Vec<AsmError>,
+}
+
+impl<R: BufRead, W: Write> Disassembler<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Disassembler {
+            reader,
+            writer,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn disassemble(&mut self) -> std::result::Result<(), AssemblyError> {
+        let mut instructions: Vec<Instruction> = Vec::new();
+        for (index, line_result) in self.reader.by_ref().lines().enumerate() {
+            let line = line_result?;
+            let word = line.trim();
+            if word.is_empty() {
+                continue;
+            }
+            match decode_word(word, index + 1) {
+                Ok(instruction) => instructions.push(instruction),
+                Err(e) => self.errors.push(e),
+            }
+        }
+
+        if !self.errors.is_empty() {
+            return Err(AssemblyError::AsmErrors(self.errors.clone()));
+        }
+
+        let targets = jump_targets(&instructions);
+        for (address, instruction) in instructions.iter().enumerate() {
+            if targets.contains(&address) {
+                writeln!(self.writer, "({})", label_name(address))?;
+            }
+            match instruction {
+                Instruction::A(a_instruction) => {
+                    let value = a_instruction.value();
+                    if is_jump_reference(&instructions, address) {
+                        writeln!(self.writer, "    @{}", label_name(value as usize))?;
+                    } else {
+                        writeln!(self.writer, "    @{}", value)?;
+                    }
+                }
+                Instruction::C(c_instruction) => writeln!(self.writer, "    {}", c_instruction)?,
+                Instruction::L(_) | Instruction::Variable(_) | Instruction::Expression(_) | Instruction::Constant(..) => {}
+            }
+        }
+        // A label placed after the last instruction resolves to the program length.
+        if targets.contains(&instructions.len()) {
+            writeln!(self.writer, "({})", label_name(instructions.len()))?;
+        }
+
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+// Parses one line of a .hack file: exactly sixteen '0'/'1' characters.
+#[allow(clippy::result_large_err)]
+pub(crate) fn parse_word(word: &str, line_number: usize) -> std::result::Result<u16, AsmError> {
+    let invalid = || {
+        AsmError::error(ErrorCode::InvalidWord, line_number, "invalid machine word")
+            .with_span(0..word.len())
+            .with_source(word)
+            .with_hint("each line of a .hack file must be exactly 16 binary digits")
+    };
+    if word.len() != 16 || !word.chars().all(|c| c == '0' || c == '1') {
+        return Err(invalid());
+    }
+    u16::from_str_radix(word, 2).map_err(|_| invalid())
+}
+
+#[allow(clippy::result_large_err)]
+fn decode_word(word: &str, line_number: usize) -> std::result::Result<Instruction, AsmError> {
+    let invalid = || {
+        AsmError::error(
+            ErrorCode::InvalidWord,
+            line_number,
+            "word does not encode a known C-instruction",
+        )
+        .with_span(0..word.len())
+        .with_source(word)
+    };
+    let value = parse_word(word, line_number)?;
+
+    if value & 0x8000 == 0 {
+        Ok(Instruction::A(AInstruction::new(value)))
+    } else {
+        CInstruction::decode(value)
+            .map(Instruction::C)
+            .ok_or_else(invalid)
+    }
+}
+
+// An A-instruction immediately followed by a jumping C-instruction loads a ROM
+// address, so it's rendered as a reference to a synthetic label.
+fn is_jump_reference(instructions: &[Instruction], address: usize) -> bool {
+    match (instructions.get(address), instructions.get(address + 1)) {
+        (Some(Instruction::A(a_instruction)), Some(Instruction::C(c_instruction))) => {
+            c_instruction.jump() != Jump::Null
+                && a_instruction.value() as usize <= instructions.len()
+        }
+        _ => false,
+    }
+}
+
+fn jump_targets(instructions: &[Instruction]) -> BTreeSet<usize> {
+    (0..instructions.len())
+        .filter(|&address| is_jump_reference(instructions, address))
+        .filter_map(|address| match &instructions[address] {
+            Instruction::A(a_instruction) => Some(a_instruction.value() as usize),
+            _ => None,
+        })
+        .collect()
+}
+
+fn label_name(address: usize) -> String {
+    format!("LABEL_{}", address)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{assembler::Assembler, SymbolTable};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_round_trip() {
+        let hack = include_str!("../tests/expected/Pong.hack");
+
+        let mut asm = Vec::new();
+        Disassembler::new(Cursor::new(hack), &mut asm)
+            .disassemble()
+            .expect("disassembly failed");
+
+        let mut reassembled = Vec::new();
+        Assembler::new(Cursor::new(asm), &mut reassembled, SymbolTable::default())
+            .assemble()
+            .expect("reassembly failed");
+
+        assert_eq!(
+            String::from_utf8(reassembled).unwrap().trim(),
+            hack.replace("\r\n", "\n").trim()
+        );
+    }
+
+    #[test]
+    fn test_synthetic_labels() {
+        let hack = "0000000000000010\n1110101010000111\n1110101010000111\n";
+        let mut asm = Vec::new();
+        Disassembler::new(Cursor::new(hack), &mut asm)
+            .disassemble()
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(asm).unwrap(),
+            "    @LABEL_2\n    0;JMP\n(LABEL_2)\n    0;JMP\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_word() {
+        let hack = "0000000000000010\n1010101010000111\n10\n";
+        let mut disassembler = Disassembler::new(Cursor::new(hack), Vec::new());
+        assert!(disassembler.disassemble().is_err());
+        assert_eq!(disassembler.errors.len(), 2);
+    }
+}
@@ -0,0 +1,280 @@
+use std::fmt;
+use std::io::BufRead;
+use std::ops::Range;
+
+use crate::disassembler::parse_word;
+use crate::error::AssemblyError;
+use crate::instruction::Instruction;
+use crate::symbol_table::{KBD, SCREEN};
+use crate::SymbolTable;
+
+pub const ROM_SIZE: usize = 32 * 1024;
+pub const RAM_SIZE: usize = 32 * 1024;
+pub const SCREEN_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Halt {
+    // `(END) @END 0;JMP` style loop that can never make progress.
+    Loop { pc: u16 },
+    // PC ran past the last instruction in ROM.
+    EndOfProgram { pc: u16 },
+    StepLimit,
+}
+
+impl fmt::Display for Halt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Halt::Loop { pc } => write!(f, "infinite loop at PC {pc}"),
+            Halt::EndOfProgram { pc } => write!(f, "end of program at PC {pc}"),
+            Halt::StepLimit => write!(f, "step limit reached"),
+        }
+    }
+}
+
+pub struct Emulator {
+    rom: Vec<u16>,
+    ram: Vec<u16>,
+    a: u16,
+    d: u16,
+    pc: u16,
+    steps: usize,
+}
+
+impl Emulator {
+    pub fn new(rom: Vec<u16>) -> Self {
+        Emulator {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            steps: 0,
+        }
+    }
+
+    pub fn from_hack<R: BufRead>(reader: R) -> std::result::Result<Self, AssemblyError> {
+        let mut rom = Vec::new();
+        let mut errors = Vec::new();
+        for (index, line_result) in reader.lines().enumerate() {
+            let line = line_result?;
+            let word = line.trim();
+            if word.is_empty() {
+                continue;
+            }
+            match parse_word(word, index + 1) {
+                Ok(word) => rom.push(word),
+                Err(e) => errors.push(e),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(AssemblyError::AsmErrors(errors));
+        }
+        Ok(Emulator::new(rom))
+    }
+
+    pub fn from_instructions(
+        instructions: &[Instruction],
+        symbol_table: &mut SymbolTable,
+    ) -> crate::Result<Self> {
+        let mut rom = Vec::with_capacity(instructions.len());
+        for (index, instruction) in instructions.iter().enumerate() {
+            if let Some(word) = instruction.encode(symbol_table, index + 1)? {
+                rom.push(word);
+            }
+        }
+        Ok(Emulator::new(rom))
+    }
+
+    pub fn a(&self) -> u16 {
+        self.a
+    }
+
+    pub fn d(&self) -> u16 {
+        self.d
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    // Registers can be set directly, as test scripts do with `set A 5`.
+    pub fn set_a(&mut self, value: u16) {
+        self.a = value;
+    }
+
+    pub fn set_d(&mut self, value: u16) {
+        self.d = value;
+    }
+
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn rom(&self) -> &[u16] {
+        &self.rom
+    }
+
+    pub fn ram(&self) -> &[u16] {
+        &self.ram
+    }
+
+    pub fn peek(&self, address: u16) -> u16 {
+        self.ram[address as usize % RAM_SIZE]
+    }
+
+    pub fn poke(&mut self, address: u16, value: u16) {
+        self.ram[address as usize % RAM_SIZE] = value;
+    }
+
+    pub fn screen(&self) -> &[u16] {
+        &self.ram[SCREEN as usize..SCREEN as usize + SCREEN_SIZE]
+    }
+
+    pub fn set_keyboard(&mut self, key: u16) {
+        self.poke(KBD, key);
+    }
+
+    // Executes a single instruction. Returns the reason execution can't
+    // continue, if any, without advancing.
+    pub fn step(&mut self) -> Option<Halt> {
+        let pc = self.pc;
+        let Some(&word) = self.rom.get(pc as usize) else {
+            return Some(Halt::EndOfProgram { pc });
+        };
+
+        if word & 0x8000 == 0 {
+            self.a = word;
+            self.pc = pc.wrapping_add(1);
+            self.steps += 1;
+            return None;
+        }
+
+        let y = if word & 0x1000 != 0 {
+            self.peek(self.a)
+        } else {
+            self.a
+        };
+        let out = alu(self.d, y, (word >> 6) & 0b111111);
+
+        // M is addressed by A as it was before this instruction.
+        let address = self.a;
+        if word & 0b001000 != 0 {
+            self.poke(address, out);
+        }
+        if word & 0b010000 != 0 {
+            self.d = out;
+        }
+        if word & 0b100000 != 0 {
+            self.a = out;
+        }
+
+        let value = out as i16;
+        let jump = (word & 0b100 != 0 && value < 0)
+            || (word & 0b010 != 0 && value == 0)
+            || (word & 0b001 != 0 && value > 0);
+        self.steps += 1;
+
+        if jump {
+            let target = address;
+            let is_halt_loop = word & 0b111 == 0b111
+                && word & 0b111000 == 0
+                && pc > 0
+                && target == pc - 1
+                && self.rom.get(target as usize) == Some(&target);
+            self.pc = target;
+            if is_halt_loop {
+                return Some(Halt::Loop { pc: target });
+            }
+        } else {
+            self.pc = pc.wrapping_add(1);
+        }
+        None
+    }
+
+    pub fn run(&mut self, max_steps: usize) -> Halt {
+        while self.steps < max_steps {
+            if let Some(halt) = self.step() {
+                return halt;
+            }
+        }
+        Halt::StepLimit
+    }
+
+    pub fn dump(&self, range: Range<u16>) -> String {
+        let mut dump = format!("A: {}  D: {}  PC: {}\n", self.a, self.d as i16, self.pc);
+        for address in range {
+            dump.push_str(&format!(
+                "RAM[{}] = {}\n",
+                address,
+                self.peek(address) as i16
+            ));
+        }
+        dump
+    }
+}
+
+// The Hack ALU, driven directly by the zx/nx/zy/ny/f/no control bits so that
+// every encoding behaves like the hardware, not just the documented mnemonics.
+fn alu(x: u16, y: u16, control: u16) -> u16 {
+    let bit = |n: u16| control & (1 << n) != 0;
+    let x = if bit(5) { 0 } else { x };
+    let x = if bit(4) { !x } else { x };
+    let y = if bit(3) { 0 } else { y };
+    let y = if bit(2) { !y } else { y };
+    let out = if bit(1) { x.wrapping_add(y) } else { x & y };
+    if bit(0) {
+        !out
+    } else {
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_counter_program() {
+        let hack = include_str!("../tests/expected/test.hack");
+        let mut emulator = Emulator::from_hack(Cursor::new(hack)).unwrap();
+
+        assert_eq!(emulator.run(1000), Halt::Loop { pc: 11 });
+        assert_eq!(emulator.peek(16), 10);
+        assert_eq!(emulator.d(), 0);
+    }
+
+    #[test]
+    fn test_from_instructions() {
+        let source = [
+            "@7", "D=A", "@x", "M=D", "@x", "M=M-1", "D=-1", "@SCREEN", "M=D",
+        ];
+        let instructions: Vec<Instruction> = source
+            .iter()
+            .map(|line| Instruction::parse(line, 1).unwrap())
+            .collect();
+        let mut emulator =
+            Emulator::from_instructions(&instructions, &mut SymbolTable::default()).unwrap();
+
+        assert_eq!(emulator.run(100), Halt::EndOfProgram { pc: 9 });
+        assert_eq!(emulator.peek(16), 6);
+        assert_eq!(emulator.screen()[0], 0xFFFF);
+    }
+
+    #[test]
+    fn test_step_limit() {
+        // @0, D=1, 0;JMP jumps back to the start rather than to itself, so it
+        // only stops at the step limit.
+        let mut emulator = Emulator::new(vec![
+            0b0000000000000000,
+            0b1110111111010000,
+            0b1110101010000111,
+        ]);
+        assert_eq!(emulator.run(50), Halt::StepLimit);
+        assert_eq!(emulator.steps(), 50);
+    }
+}
@@ -1,31 +1,222 @@
 use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
 
+use crate::lint::Lint;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Internal,
+    InvalidInstruction,
+    InvalidDest,
+    InvalidComp,
+    InvalidJump,
+    MissingAddress,
+    NonAscii,
+    ReadFailure,
+    InvalidWord,
+    InvalidDirective,
+    MacroArguments,
+    MacroRecursion,
+    DuplicateMacro,
+    IncludeNotFound,
+    IncludeCycle,
+    DuplicateSymbol,
+    ValueOutOfRange,
+    InvalidExpression,
+    ProgramTooLarge,
+    InvalidLabel,
+    InvalidVmCommand,
+    InvalidScript,
+    NonCanonical,
+    ShadowedSymbol,
+    LabelAsVariable,
+    NotOptimized,
+    Lint(Lint),
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Internal => "E0000",
+            ErrorCode::InvalidInstruction => "E0001",
+            ErrorCode::InvalidDest => "E0002",
+            ErrorCode::InvalidComp => "E0003",
+            ErrorCode::InvalidJump => "E0004",
+            ErrorCode::MissingAddress => "E0005",
+            ErrorCode::NonAscii => "E0006",
+            ErrorCode::ReadFailure => "E0007",
+            ErrorCode::InvalidWord => "E0008",
+            ErrorCode::InvalidDirective => "E0009",
+            ErrorCode::MacroArguments => "E0010",
+            ErrorCode::MacroRecursion => "E0011",
+            ErrorCode::DuplicateMacro => "E0012",
+            ErrorCode::IncludeNotFound => "E0013",
+            ErrorCode::IncludeCycle => "E0014",
+            ErrorCode::DuplicateSymbol => "E0015",
+            ErrorCode::ValueOutOfRange => "E0016",
+            ErrorCode::InvalidExpression => "E0017",
+            ErrorCode::ProgramTooLarge => "E0018",
+            ErrorCode::InvalidLabel => "E0019",
+            ErrorCode::InvalidVmCommand => "E0020",
+            ErrorCode::InvalidScript => "E0021",
+            ErrorCode::NonCanonical => "W0001",
+            ErrorCode::ShadowedSymbol => "W0002",
+            ErrorCode::LabelAsVariable => "W0003",
+            ErrorCode::NotOptimized => "W0004",
+            ErrorCode::Lint(lint) => lint.code(),
+        }
+    }
+}
+
+// A diagnostic tied to a single source line. `span` is a byte range into
+// `source`, which holds the full line as it appeared in the input.
 #[derive(Debug, Clone)]
-pub enum AsmError {
-    InvalidInstruction { line: usize, instruction: String },
-    SyntaxError { line: usize, message: String },
+pub struct AsmError {
+    pub severity: Severity,
+    pub code: ErrorCode,
+    pub message: String,
+    // Set when the line is in an included file rather than the one being assembled.
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub span: Range<usize>,
+    pub source: String,
+    pub hint: Option<String>,
+    pub notes: Vec<String>,
+}
+
+impl AsmError {
+    pub fn error<S: Into<String>>(code: ErrorCode, line: usize, message: S) -> Self {
+        AsmError {
+            severity: Severity::Error,
+            code,
+            message: message.into(),
+            file: None,
+            line,
+            span: 0..0,
+            source: String::new(),
+            hint: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn warning<S: Into<String>>(code: ErrorCode, line: usize, message: S) -> Self {
+        AsmError {
+            severity: Severity::Warning,
+            ..AsmError::error(code, line, message)
+        }
+    }
+
+    pub fn into_warning(mut self) -> Self {
+        self.severity = Severity::Warning;
+        self
+    }
+
+    pub fn with_span(mut self, span: Range<usize>) -> Self {
+        self.span = span;
+        self
+    }
+
+    pub fn with_hint<S: Into<String>>(mut self, hint: S) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+
+    pub fn with_note<S: Into<String>>(mut self, note: S) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_file<P: Into<PathBuf>>(mut self, file: P) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    pub fn with_source<S: Into<String>>(mut self, source: S) -> Self {
+        self.source = source.into();
+        self
+    }
+
+    // Moves the span right by `offset` bytes, for errors raised while parsing
+    // a fragment that starts part way into the line.
+    pub fn shifted(mut self, offset: usize) -> Self {
+        self.span = self.span.start + offset..self.span.end + offset;
+        self
+    }
+
+    pub fn column(&self) -> usize {
+        self.span.start + 1
+    }
 }
 
 impl std::error::Error for AsmError {}
 
 impl fmt::Display for AsmError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            AsmError::InvalidInstruction { line, instruction } => {
-                write!(f, "Invalid instruction on line {line}: {instruction}")
-            }
-            AsmError::SyntaxError { line, message } => {
-                write!(f, "Syntax error on line {line}: {message}")
-            }
+        writeln!(f, "{}[{}]: {}", self.severity, self.code.as_str(), self.message)?;
+        let gutter = " ".repeat(self.line.to_string().len());
+        match &self.file {
+            Some(file) => writeln!(f, "{gutter}--> {}:{}:{}", file.display(), self.line, self.column())?,
+            None => writeln!(f, "{gutter}--> line {}:{}", self.line, self.column())?,
+        }
+        if !self.source.is_empty() {
+            let start = self.span.start.min(self.source.len());
+            let width = self.span.end.saturating_sub(start).max(1);
+            writeln!(f, "{gutter} |")?;
+            writeln!(f, "{} | {}", self.line, self.source)?;
+            writeln!(f, "{gutter} | {}{}", " ".repeat(start), "^".repeat(width))?;
+        }
+        if let Some(hint) = &self.hint {
+            writeln!(f, "{gutter} = help: {hint}")?;
         }
+        for note in &self.notes {
+            writeln!(f, "{gutter} = note: {note}")?;
+        }
+        Ok(())
+    }
+}
+
+// Everything reported while assembling from memory: the errors that stopped
+// it along with any warnings raised before that point.
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    pub errors: Vec<AsmError>,
+    pub warnings: Vec<AsmError>,
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", AssemblyError::AsmErrors(self.errors.clone()))?;
+        for warning in &self.warnings {
+            writeln!(f)?;
+            write!(f, "{}", warning)?;
+        }
+        Ok(())
     }
 }
 
+impl std::error::Error for Diagnostics {}
+
 impl Clone for AssemblyError {
     fn clone(&self) -> Self {
         match self {
             AssemblyError::AsmErrors(errors) => AssemblyError::AsmErrors(errors.clone()),
-            AssemblyError::Other(_) => AssemblyError::Other(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Cannot clone Box<dyn Error>"))),
+            AssemblyError::Other(_) => AssemblyError::Other(Box::new(std::io::Error::other("Cannot clone Box<dyn Error>"))),
         }
     }
 }
@@ -53,9 +244,14 @@ impl fmt::Display for AssemblyError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             AssemblyError::AsmErrors(errors) => {
-                writeln!(f, "Assembly encountered the following errors:")?;
+                let count = errors
+                    .iter()
+                    .filter(|error| error.severity == Severity::Error)
+                    .count();
+                writeln!(f, "Assembly encountered {} error(s):", count)?;
                 for error in errors {
-                    writeln!(f, "  - {}", error)?;
+                    writeln!(f)?;
+                    write!(f, "{}", error)?;
                 }
                 Ok(())
             }
@@ -81,19 +277,29 @@ mod test {
 
     #[test]
     fn test_asm_error_display() {
-        let error = AsmError::InvalidInstruction {
-            line: 1,
-            instruction: "MOV A, B".to_string(),
-        };
-        assert_eq!(error.to_string(), "Invalid instruction on line 1: MOV A, B");
-
-        let error = AsmError::SyntaxError {
-            line: 2,
-            message: "Expected a comma".to_string(),
-        };
+        let error = AsmError::error(ErrorCode::InvalidComp, 12, "invalid computation `M+Q`")
+            .with_span(2..5)
+            .shifted(2)
+            .with_source("  D=M+Q;JXX")
+            .with_hint("did you mean `M+1`?");
+        assert_eq!(
+            error.to_string(),
+            [
+                "error[E0003]: invalid computation `M+Q`",
+                "  --> line 12:5",
+                "   |",
+                "12 |   D=M+Q;JXX",
+                "   |     ^^^",
+                "   = help: did you mean `M+1`?",
+                "",
+            ]
+            .join("\n")
+        );
+
+        let error = AsmError::error(ErrorCode::ReadFailure, 2, "failed to read line");
         assert_eq!(
             error.to_string(),
-            "Syntax error on line 2: Expected a comma"
+            "error[E0007]: failed to read line\n --> line 2:1\n"
         );
     }
 }
@@ -0,0 +1,331 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::error::ErrorCode;
+use crate::instruction::MAX_A_VALUE;
+use crate::lexer::{tokenize, Token, TokenKind};
+use crate::{AsmError, SymbolKind, SymbolTable};
+
+// An integer expression in an A-instruction, such as `SCREEN+32` or
+// `(ROWS*32)`. Spans are byte ranges into the instruction it was parsed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Range<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExprKind {
+    Number(i64),
+    Symbol(String),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+impl BinaryOp {
+    fn symbol(&self) -> char {
+        match self {
+            BinaryOp::Add => '+',
+            BinaryOp::Subtract => '-',
+            BinaryOp::Multiply => '*',
+            BinaryOp::Divide => '/',
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Add | BinaryOp::Subtract => 1,
+            BinaryOp::Multiply | BinaryOp::Divide => 2,
+        }
+    }
+}
+
+impl Expr {
+    // Parses `text`, which starts `offset` bytes into the instruction.
+    #[allow(clippy::result_large_err)]
+    pub fn parse(text: &str, offset: usize, line_number: usize) -> Result<Expr, AsmError> {
+        let mut parser = Parser {
+            tokens: tokenize(text),
+            position: 0,
+            end: text.len(),
+            offset,
+            line_number,
+        };
+        let expr = parser.expression()?;
+        if let Some(token) = parser.peek() {
+            let start = token.span.start;
+            return Err(parser.error(
+                format!("unexpected `{}` in expression", &text[start..]),
+                start..text.len(),
+            ));
+        }
+        Ok(expr)
+    }
+
+    // True if the expression doesn't refer to any symbols.
+    pub fn is_constant(&self) -> bool {
+        match &self.kind {
+            ExprKind::Number(_) => true,
+            ExprKind::Symbol(_) => false,
+            ExprKind::Negate(operand) => operand.is_constant(),
+            ExprKind::Binary(_, left, right) => left.is_constant() && right.is_constant(),
+        }
+    }
+
+    // Every symbol the expression refers to, in order.
+    pub fn symbols(&self) -> Vec<&str> {
+        match &self.kind {
+            ExprKind::Number(_) => Vec::new(),
+            ExprKind::Symbol(name) => vec![name.as_str()],
+            ExprKind::Negate(operand) => operand.symbols(),
+            ExprKind::Binary(_, left, right) => {
+                let mut symbols = left.symbols();
+                symbols.extend(right.symbols());
+                symbols
+            }
+        }
+    }
+
+    // Resolves symbols against labels, constants and predefined symbols.
+    // Variables are only allocated once expressions have been evaluated, so
+    // they can't be used here.
+    #[allow(clippy::result_large_err)]
+    pub fn evaluate(&self, symbol_table: &SymbolTable, line_number: usize) -> Result<i64, AsmError> {
+        let error = |message: String, span: &Range<usize>| {
+            AsmError::error(ErrorCode::InvalidExpression, line_number, message).with_span(span.clone())
+        };
+        match &self.kind {
+            ExprKind::Number(value) => Ok(*value),
+            ExprKind::Symbol(name) => match symbol_table.get(name) {
+                Some(symbol) if symbol.kind != SymbolKind::Variable => Ok(symbol.address as i64),
+                _ => Err(error(format!("unknown symbol `{}` in expression", name), &self.span)
+                    .with_hint("expressions can use labels, constants and predefined symbols, but not variables")),
+            },
+            ExprKind::Negate(operand) => Ok(-operand.evaluate(symbol_table, line_number)?),
+            ExprKind::Binary(op, left, right) => {
+                let left = left.evaluate(symbol_table, line_number)?;
+                let right = right.evaluate(symbol_table, line_number)?;
+                let result = match op {
+                    BinaryOp::Add => left.checked_add(right),
+                    BinaryOp::Subtract => left.checked_sub(right),
+                    BinaryOp::Multiply => left.checked_mul(right),
+                    BinaryOp::Divide if right == 0 => {
+                        return Err(error("division by zero".to_string(), &self.span));
+                    }
+                    BinaryOp::Divide => left.checked_div(right),
+                };
+                result.ok_or_else(|| error("expression overflows".to_string(), &self.span))
+            }
+        }
+    }
+
+    fn fmt_with_precedence(&self, f: &mut fmt::Formatter<'_>, parent: u8) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Number(value) => write!(f, "{}", value),
+            ExprKind::Symbol(name) => write!(f, "{}", name),
+            ExprKind::Negate(operand) => {
+                write!(f, "-")?;
+                operand.fmt_with_precedence(f, 3)
+            }
+            ExprKind::Binary(op, left, right) => {
+                let precedence = op.precedence();
+                if precedence < parent {
+                    write!(f, "(")?;
+                }
+                left.fmt_with_precedence(f, precedence)?;
+                write!(f, "{}", op.symbol())?;
+                // Both operators at a level are left-associative, so a
+                // right operand at the same level needs its parentheses.
+                right.fmt_with_precedence(f, precedence + 1)?;
+                if precedence < parent {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with_precedence(f, 0)
+    }
+}
+
+// Decimal, `0x` hexadecimal or `0b` binary.
+pub fn parse_number(text: &str) -> Option<i64> {
+    let (digits, radix) = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (hex, 16)
+    } else if let Some(binary) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        (binary, 2)
+    } else {
+        (text, 10)
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    i64::from_str_radix(digits, radix).ok()
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    position: usize,
+    // Length of the text, for errors at the end of it.
+    end: usize,
+    offset: usize,
+    line_number: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: String, span: Range<usize>) -> AsmError {
+        AsmError::error(ErrorCode::InvalidExpression, self.line_number, message)
+            .with_span(span.start + self.offset..span.end + self.offset)
+    }
+
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.position)
+    }
+
+    // The span from the start of token `start` to the end of the last token consumed.
+    fn span(&self, start: usize) -> Range<usize> {
+        self.tokens[start].span.start + self.offset..self.tokens[self.position - 1].span.end + self.offset
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn expression(&mut self) -> Result<Expr, AsmError> {
+        self.binary(1)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn binary(&mut self, precedence: u8) -> Result<Expr, AsmError> {
+        if precedence > 2 {
+            return self.unary();
+        }
+        let start = self.position;
+        let mut left = self.binary(precedence + 1)?;
+        loop {
+            let op = match self.peek().map(|token| token.kind) {
+                Some(TokenKind::Punct('+')) => BinaryOp::Add,
+                Some(TokenKind::Punct('-')) => BinaryOp::Subtract,
+                Some(TokenKind::Punct('*')) => BinaryOp::Multiply,
+                Some(TokenKind::Punct('/')) => BinaryOp::Divide,
+                _ => return Ok(left),
+            };
+            if op.precedence() != precedence {
+                return Ok(left);
+            }
+            self.position += 1;
+            let right = self.binary(precedence + 1)?;
+            left = Expr {
+                kind: ExprKind::Binary(op, Box::new(left), Box::new(right)),
+                span: self.span(start),
+            };
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn unary(&mut self) -> Result<Expr, AsmError> {
+        let start = self.position;
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.error(
+                "expected a number or symbol at the end of the expression".to_string(),
+                self.end..self.end + 1,
+            ));
+        };
+        self.position += 1;
+        let kind = match token.kind {
+            TokenKind::Punct('-') => ExprKind::Negate(Box::new(self.unary()?)),
+            TokenKind::Punct('(') => {
+                let inner = self.expression()?;
+                if self.peek().map(|token| token.kind) != Some(TokenKind::Punct(')')) {
+                    return Err(self
+                        .error("unclosed `(` in expression".to_string(), token.span)
+                        .with_hint("add a matching `)`"));
+                }
+                self.position += 1;
+                inner.kind
+            }
+            TokenKind::Symbol => ExprKind::Symbol(token.text.to_string()),
+            TokenKind::Number => match parse_number(token.text) {
+                Some(value) => ExprKind::Number(value),
+                None if token.text.chars().all(|c| c.is_ascii_digit()) => {
+                    return Err(AsmError::error(
+                        ErrorCode::ValueOutOfRange,
+                        self.line_number,
+                        format!("`{}` is too large", token.text),
+                    )
+                    .with_span(self.span(start))
+                    .with_hint(format!("A-instructions can only load values from 0 to {}", MAX_A_VALUE)));
+                }
+                None => {
+                    return Err(self
+                        .error(format!("invalid number `{}`", token.text), token.span)
+                        .with_hint("numbers are decimal, `0x` hex or `0b` binary; symbols can't start with a digit"));
+                }
+            },
+            TokenKind::Punct(c) => {
+                return Err(self.error(format!("expected a number or symbol, found `{}`", c), token.span));
+            }
+        };
+        Ok(Expr {
+            kind,
+            span: self.span(start),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[allow(clippy::result_large_err)]
+    fn evaluate(text: &str, symbol_table: &SymbolTable) -> Result<i64, AsmError> {
+        Expr::parse(text, 0, 1)?.evaluate(symbol_table, 1)
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut symbol_table = SymbolTable::default();
+        symbol_table.add_constant("ROWS".to_string(), 16);
+        symbol_table.add_label("LOOP".to_string(), 10);
+
+        assert_eq!(evaluate("SCREEN+32", &symbol_table).unwrap(), 16416);
+        assert_eq!(evaluate("LOOP-1", &symbol_table).unwrap(), 9);
+        assert_eq!(evaluate("(ROWS*32)", &symbol_table).unwrap(), 512);
+        assert_eq!(evaluate("2 + 3 * (4 - 1)", &symbol_table).unwrap(), 11);
+        assert_eq!(evaluate("10-4-3", &symbol_table).unwrap(), 3);
+        assert_eq!(evaluate("-0x10 + 0b101", &symbol_table).unwrap(), -11);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut symbol_table = SymbolTable::default();
+        symbol_table.add_variable("x".to_string()).unwrap();
+
+        let error = evaluate("SCREEN+1abc", &symbol_table).unwrap_err();
+        assert_eq!(error.span, 7..11);
+        let error = evaluate("(1+2", &symbol_table).unwrap_err();
+        assert_eq!(error.message, "unclosed `(` in expression");
+        let error = evaluate("1+", &symbol_table).unwrap_err();
+        assert_eq!(error.span, 2..3);
+        let error = evaluate("x+1", &symbol_table).unwrap_err();
+        assert_eq!(error.span, 0..1);
+        assert_eq!(evaluate("1/(2-2)", &symbol_table).unwrap_err().message, "division by zero");
+    }
+
+    #[test]
+    fn test_display() {
+        for text in ["SCREEN+32", "(A+B)*C", "A-(B-C)", "A-B-C", "-(X+1)"] {
+            assert_eq!(Expr::parse(text, 0, 1).unwrap().to_string(), text);
+        }
+        assert_eq!(Expr::parse("( ROWS * 32 )", 0, 1).unwrap().to_string(), "ROWS*32");
+    }
+}
@@ -0,0 +1,213 @@
+use crate::lexer::{is_symbol, is_symbol_char};
+
+// A source line split into the parts the formatter cares about. Unlike
+// `sanitize_line`, nothing is thrown away: comments and blank lines survive.
+#[derive(Debug, PartialEq, Eq)]
+struct Line<'a> {
+    // Whether the line started with whitespace.
+    indented: bool,
+    code: &'a str,
+    // The trailing comment, including its `//`.
+    comment: Option<&'a str>,
+}
+
+fn split(text: &str) -> Line<'_> {
+    let (code, comment) = match text.find("//") {
+        Some(index) => (&text[..index], Some(text[index..].trim_end())),
+        None => (text, None),
+    };
+    Line {
+        indented: text.starts_with(char::is_whitespace),
+        code: code.trim(),
+        comment,
+    }
+}
+
+// Rewrites `source` in the canonical style: labels and directives at column 0,
+// everything else indented by `indent` spaces, no spaces around operators,
+// and trailing comments aligned within each run of non-blank lines.
+pub fn format(source: &str, indent: usize) -> String {
+    let padding = " ".repeat(indent);
+    // Files written with CRLF line endings keep them.
+    let newline = match source.contains("\r\n") {
+        true => "\r\n",
+        false => "\n",
+    };
+    let lines: Vec<(String, Option<&str>)> = source
+        .lines()
+        .map(|text| {
+            let line = split(text);
+            let code = if line.code.is_empty() {
+                match line.indented && line.comment.is_some() {
+                    true => padding.clone(),
+                    false => String::new(),
+                }
+            } else if line.code.starts_with('(') {
+                tight(line.code)
+            } else if line.code.starts_with('.') {
+                words(line.code)
+            } else if is_macro_invocation(line.code) {
+                format!("{}{}", padding, words(line.code))
+            } else {
+                format!("{}{}", padding, tight(line.code))
+            };
+            (code, line.comment)
+        })
+        .collect();
+
+    let mut output = String::new();
+    for block in lines.split(|(code, comment)| code.is_empty() && comment.is_none()) {
+        // Comment-only lines keep their comment where it is.
+        let column = block
+            .iter()
+            .filter(|(code, comment)| !code.trim().is_empty() && comment.is_some())
+            .map(|(code, _)| code.len() + 2)
+            .max()
+            .unwrap_or(0);
+        for (code, comment) in block {
+            match comment {
+                Some(comment) if !code.trim().is_empty() => {
+                    output.push_str(&format!("{:width$}{}", code, comment, width = column));
+                }
+                Some(comment) => output.push_str(&format!("{}{}", code, comment)),
+                None => output.push_str(code),
+            }
+            output.push_str(newline);
+        }
+        output.push_str(newline);
+    }
+    // The split adds a separator after the last block too.
+    let trimmed = output.trim_end_matches(['\r', '\n']).len();
+    output.truncate(trimmed);
+    if !output.is_empty() {
+        output.push_str(newline);
+    }
+    output
+}
+
+// Removes whitespace except where it separates two symbols, so `D = M + 1`
+// becomes `D=M+1` but a malformed `@x y` isn't turned into a valid `@xy`.
+fn tight(code: &str) -> String {
+    let mut output = String::new();
+    let mut pending_space = false;
+    for c in code.chars() {
+        if c.is_whitespace() {
+            pending_space = true;
+            continue;
+        }
+        if pending_space && output.ends_with(is_symbol_char) && is_symbol_char(c) {
+            output.push(' ');
+        }
+        pending_space = false;
+        output.push(c);
+    }
+    output
+}
+
+// Collapses whitespace to single spaces and writes lists as `a, b`, leaving
+// quoted strings alone. Used for directives and macro invocations, where
+// spaces separate the name from its arguments.
+fn words(code: &str) -> String {
+    let mut output = String::new();
+    let mut in_quotes = false;
+    let mut pending_space = false;
+    for c in code.chars() {
+        if in_quotes {
+            in_quotes = c != '"';
+            output.push(c);
+            continue;
+        }
+        if c.is_whitespace() {
+            pending_space = true;
+            continue;
+        }
+        if c == ',' {
+            pending_space = false;
+            output.push(',');
+            output.push(' ');
+            continue;
+        }
+        if pending_space && !output.ends_with(' ') {
+            output.push(' ');
+        }
+        pending_space = false;
+        in_quotes = c == '"';
+        output.push(c);
+    }
+    output.trim_end().to_string()
+}
+
+// `NAME args...`: a symbol followed by whitespace and something other than
+// an operator, which no instruction looks like.
+pub fn is_macro_invocation(code: &str) -> bool {
+    let Some((name, arguments)) = code.split_once(char::is_whitespace) else {
+        return false;
+    };
+    let arguments = arguments.trim_start();
+    is_symbol(name)
+        && !arguments.is_empty()
+        && !arguments.starts_with(['=', ';', '+', '-', '&', '|'])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        assert_eq!(
+            split("  D = M // copy // it  "),
+            Line {
+                indented: true,
+                code: "D = M",
+                comment: Some("// copy // it"),
+            }
+        );
+        assert_eq!(
+            split("(LOOP)"),
+            Line {
+                indented: false,
+                code: "(LOOP)",
+                comment: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let source = "\
+// Adds one
+  .equ  N ,5
+.include   \"lib  file.asm\"
+
+( LOOP )   // top
+@ i // load
+      D = M + 1
+  0 ; JMP
+    // indented comment
+  push  D,  A
+@x y
+
+
+";
+        let expected = "\
+// Adds one
+.equ N, 5
+.include \"lib  file.asm\"
+
+(LOOP)  // top
+    @i  // load
+    D=M+1
+    0;JMP
+    // indented comment
+    push D, A
+    @x y
+";
+        assert_eq!(format(source, 4), expected);
+        assert_eq!(format(expected, 4), expected);
+
+        let crlf = |text: &str| text.replace('\n', "\r\n");
+        assert_eq!(format(&crlf(source), 4), crlf(expected));
+        assert_eq!(format(&crlf(expected), 4), crlf(expected));
+    }
+}
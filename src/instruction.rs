@@ -1,4 +1,11 @@
-use crate::AsmError;
+use std::fmt;
+use std::ops::Range;
+
+use crate::error::ErrorCode;
+use crate::expression::{parse_number, Expr};
+use crate::lexer::{is_symbol, tokenize, TokenKind};
+use crate::suggest::closest;
+use crate::{AsmError, Options, SymbolTable};
 
 
 
@@ -12,75 +19,448 @@ pub enum Instruction {
     C(CInstruction),
     L(String),
     Variable(String),
+    // `@` followed by an expression, resolved once the symbol table is built.
+    Expression(Expr),
+    // `.equ NAME value`; feeds the symbol table and occupies no ROM.
+    Constant(String, u16),
 }
 impl Instruction {
+    #[allow(clippy::result_large_err)]
     pub fn parse<S: AsRef<str>>(
         instruction: S,
         line_number: usize,
     ) -> Result<Instruction, AsmError> {
-        let instruction = instruction.as_ref().trim();
+        Self::parse_with(instruction, line_number, &Options::default(), &mut Vec::new())
+    }
 
+    // Like `parse`, but honours `options` and collects any warnings instead of
+    // discarding them. Spans in both are relative to `instruction`.
+    #[allow(clippy::result_large_err)]
+    pub fn parse_with<S: AsRef<str>>(
+        instruction: S,
+        line_number: usize,
+        options: &Options,
+        warnings: &mut Vec<AsmError>,
+    ) -> Result<Instruction, AsmError> {
+        let raw = instruction.as_ref();
+        let offset = raw.len() - raw.trim_start().len();
+        let mut new_warnings = Vec::new();
+        let result = Self::parse_trimmed(raw.trim(), line_number, options, &mut new_warnings)
+            .map_err(|e| e.shifted(offset));
+        warnings.extend(new_warnings.into_iter().map(|w| w.shifted(offset)));
+        result
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn parse_trimmed(
+        instruction: &str,
+        line_number: usize,
+        options: &Options,
+        warnings: &mut Vec<AsmError>,
+    ) -> Result<Instruction, AsmError> {
         if  let Some(symbol) = instruction.strip_prefix('@')  {
             if symbol.is_empty() {
-                return Err(AsmError::InvalidInstruction {
-                    line: line_number,
-                    instruction: instruction.to_string(),
-                });
+                return Err(
+                    AsmError::error(ErrorCode::MissingAddress, line_number, "missing address after `@`")
+                        .with_span(0..1)
+                        .with_hint("expected a number or symbol, e.g. `@16` or `@LOOP`"),
+                );
             }
 
-            if let Ok(num) = symbol.parse::<u16>() {
+            if let Ok(num) = symbol.parse::<i64>() {
                 // Directly addressable number
-                Ok(Instruction::A(AInstruction::new(num)))
-            } else {
+                Ok(Instruction::A(AInstruction::new(a_value(num, &(1..instruction.len()), line_number)?)))
+            } else if is_symbol(symbol) {
                 // Variable
                 Ok(Instruction::Variable(symbol.to_string()))
+            } else {
+                let expr = Expr::parse(symbol, 1, line_number)?;
+                if expr.is_constant() {
+                    // Nothing to resolve, so range errors can be reported straight away.
+                    let value = expr.evaluate(&SymbolTable::default(), line_number)?;
+                    Ok(Instruction::A(AInstruction::new(a_value(value, &expr.span, line_number)?)))
+                } else {
+                    Ok(Instruction::Expression(expr))
+                }
             }
-        } else if instruction.starts_with('(') && instruction.ends_with(')') {
-            let label = instruction.trim_matches(|c: char| c == '(' || c == ')');
-            return Ok(Instruction::L(label.to_string()));
+        } else if matches!(instruction.split_whitespace().next(), Some(".equ" | ".define")) {
+            parse_constant(instruction, line_number)
+        } else if instruction.starts_with('(') {
+            parse_label(instruction, line_number)
         } else {
             // Assuming the rest are C-instructions
-            return parse_c_instruction(instruction, line_number);
+            parse_c_instruction(instruction, line_number, options, warnings)
+        }
+    }
+
+    // Labels and constants don't take up a word of ROM.
+    pub fn occupies_rom(&self) -> bool {
+        !matches!(self, Instruction::L(_) | Instruction::Constant(..))
+    }
+
+    // Returns the machine word for this instruction, allocating variables as
+    // needed. Labels don't occupy ROM and encode to `None`. `line_number` is
+    // for errors from expressions that don't fit in an A-instruction.
+    pub fn encode(&self, symbol_table: &mut SymbolTable, line_number: usize) -> crate::Result<Option<u16>> {
+        match self {
+            Instruction::A(a_instruction) => Ok(Some(a_instruction.value())),
+            Instruction::C(c_instruction) => Ok(Some(c_instruction.encode())),
+            Instruction::Variable(variable_name) => {
+                Ok(Some(symbol_table.add_variable(variable_name.clone())?))
+            }
+            Instruction::Expression(expr) => {
+                let value = expr.evaluate(symbol_table, line_number)?;
+                Ok(Some(a_value(value, &expr.span, line_number)?))
+            }
+            Instruction::L(_) | Instruction::Constant(..) => Ok(None),
         }
     }
 }
 
+// Checks that `value` fits in an A-instruction.
+#[allow(clippy::result_large_err)]
+pub fn a_value(value: i64, span: &Range<usize>, line_number: usize) -> Result<u16, AsmError> {
+    if (0..=MAX_A_VALUE as i64).contains(&value) {
+        return Ok(value as u16);
+    }
+    let error = AsmError::error(
+        ErrorCode::ValueOutOfRange,
+        line_number,
+        format!("value {} doesn't fit in an A-instruction", value),
+    )
+    .with_span(span.clone());
+    Err(match value {
+        -1 => error.with_hint("A-instructions can't be negative, but `-1` is a computation: use e.g. `D=-1`"),
+        value if value < 0 && value >= -(MAX_A_VALUE as i64) => error.with_hint(format!(
+            "A-instructions can't be negative; load `@{}` and negate it with `D=-A`",
+            -value
+        )),
+        // Values with the top bit set would be read back as C-instructions.
+        _ => error.with_hint(format!("A-instructions can only load values from 0 to {}", MAX_A_VALUE)),
+    })
+}
+
 impl CInstruction {
     pub fn to_binary(&self) -> String {
-        let a_bit = if self.comp.clone().uses_m() { "1" } else { "0" };
-        let dest = self.dest.clone() as u16;
-        let comp = self.comp.clone().convert_to_binary();
-        let jump = self.jump.clone() as u16;
-        format!("111{}{:06b}{:03b}{:03b}", a_bit, comp, dest, jump)
+        format!("{:016b}", self.encode())
+    }
+
+    pub fn encode(&self) -> u16 {
+        let a_bit = if self.comp.uses_m() { 1 } else { 0 };
+        let dest = self.dest as u16;
+        let comp = self.comp.convert_to_binary();
+        let jump = self.jump as u16;
+        0b111 << 13 | a_bit << 12 | comp << 6 | dest << 3 | jump
+    }
+
+    // Reverse of `encode`. Returns `None` for words that are not C-instructions
+    // or whose computation bits don't match a known mnemonic.
+    pub fn decode(word: u16) -> Option<CInstruction> {
+        if word >> 13 != 0b111 {
+            return None;
+        }
+        let comp = Comp::from_bits((word >> 6) & 0b1111111)?;
+        let dest = Dest::from_bits((word >> 3) & 0b111);
+        let jump = Jump::from_bits(word & 0b111);
+        Some(CInstruction::new(dest, comp, jump))
+    }
+
+    pub fn dest(&self) -> Dest {
+        self.dest
+    }
+
+    pub fn comp(&self) -> Comp {
+        self.comp
+    }
+
+    pub fn jump(&self) -> Jump {
+        self.jump
+    }
+
+    // True if the instruction reads or writes RAM[A].
+    pub fn uses_memory(&self) -> bool {
+        self.comp.uses_m() || self.writes_memory()
+    }
+
+    pub fn writes_memory(&self) -> bool {
+        self.dest as u16 & Dest::M as u16 != 0
     }
 }
 
-fn parse_c_instruction(instruction: &str, line_number: usize) -> Result<Instruction, AsmError> {
+impl fmt::Display for CInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.dest != Dest::Null {
+            write!(f, "{}=", self.dest.mnemonic())?;
+        }
+        write!(f, "{}", self.comp.mnemonic())?;
+        if self.jump != Jump::Null {
+            write!(f, ";{}", self.jump.mnemonic())?;
+        }
+        Ok(())
+    }
+}
+
+// `(NAME)`: a single symbol in parentheses with nothing after it.
+#[allow(clippy::result_large_err)]
+fn parse_label(instruction: &str, line_number: usize) -> Result<Instruction, AsmError> {
+    let error = |message: String, span: Range<usize>| {
+        AsmError::error(ErrorCode::InvalidLabel, line_number, message).with_span(span)
+    };
+    let name_hint = "label names can only contain letters, digits, `_`, `.`, `$` and `:`";
+    let tokens = tokenize(instruction);
+    let is = |index: usize, kind: TokenKind| tokens.get(index).map(|token| token.kind) == Some(kind);
+
+    if is(1, TokenKind::Symbol) && is(2, TokenKind::Punct(')')) {
+        return match tokens.get(3) {
+            Some(extra) => Err(error(
+                format!("unexpected `{}` after label", &instruction[extra.span.start..]),
+                extra.span.start..instruction.len(),
+            )
+            .with_hint("put the instruction on a line of its own")),
+            None => Ok(Instruction::L(tokens[1].text.to_string())),
+        };
+    }
+    Err(match tokens.get(1) {
+        None => error("missing label name after `(`".to_string(), 0..1).with_hint("write a label as `(NAME)`"),
+        Some(token) if token.kind == TokenKind::Punct(')') => {
+            error("empty label".to_string(), 0..token.span.end).with_hint("write a label as `(NAME)`")
+        }
+        Some(token) if token.kind == TokenKind::Number => error(
+            format!("label `{}` starts with a digit", token.text),
+            token.span.clone(),
+        )
+        .with_hint("label names can't start with a digit"),
+        Some(token) if token.kind == TokenKind::Symbol => match tokens.get(2) {
+            None => error(format!("missing `)` after label `{}`", token.text), 0..instruction.len()),
+            Some(next) if matches!(next.kind, TokenKind::Symbol | TokenKind::Number) => error(
+                format!("unexpected `{}` in label `{}`", next.text, token.text),
+                next.span.clone(),
+            )
+            .with_hint("labels can't contain spaces"),
+            Some(next) => error(
+                format!("unexpected `{}` in label `{}`", next.text, token.text),
+                next.span.clone(),
+            )
+            .with_hint(name_hint),
+        },
+        Some(token) => error(format!("unexpected `{}` in label", token.text), token.span.clone()).with_hint(name_hint),
+    })
+}
+
+// `.equ NAME value` (or `.define`), with an optional comma after the name.
+#[allow(clippy::result_large_err)]
+fn parse_constant(instruction: &str, line_number: usize) -> Result<Instruction, AsmError> {
+    let error = |code, message: String, span: Range<usize>| {
+        AsmError::error(code, line_number, message).with_span(span)
+    };
+    // Words with their spans: tokens that touch join up, so `-5` and `.equ`
+    // are one word each, and commas separate like whitespace.
+    let mut words: Vec<(&str, Range<usize>)> = Vec::new();
+    for token in tokenize(instruction) {
+        match words.last_mut() {
+            _ if token.kind == TokenKind::Punct(',') => {}
+            Some((word, span)) if span.end == token.span.start => {
+                span.end = token.span.end;
+                *word = &instruction[span.clone()];
+            }
+            _ => words.push((token.text, token.span)),
+        }
+    }
+    if let Some((_, extra)) = words.get(3) {
+        let start = extra.start;
+        return Err(error(
+            ErrorCode::InvalidDirective,
+            format!("unexpected `{}` after the value", &instruction[start..]),
+            start..instruction.len(),
+        ));
+    }
+    let [(directive, _), (name, ref name_span), (value, ref value_span)] = words[..] else {
+        let directive = words[0].0;
+        return Err(error(
+            ErrorCode::InvalidDirective,
+            format!("expected a name and a value after `{}`", directive),
+            0..instruction.len(),
+        )
+        .with_hint(format!("e.g. `{} ROWS 32`", directive)));
+    };
+    if !is_symbol(name) {
+        return Err(error(
+            ErrorCode::InvalidDirective,
+            format!("invalid constant name `{}`", name),
+            name_span.clone(),
+        )
+        .with_hint("names are letters, digits, `_`, `.`, `$` and `:`, and can't start with a digit"));
+    }
+    let value = match parse_number(value) {
+        Some(value) if (0..=MAX_A_VALUE as i64).contains(&value) => value as u16,
+        Some(_) => {
+            return Err(error(
+                ErrorCode::ValueOutOfRange,
+                format!("`{}` doesn't fit in 15 bits", value),
+                value_span.clone(),
+            )
+            .with_hint(format!("`{}` values must be between 0 and {} so `@{}` can load them", directive, MAX_A_VALUE, name)))
+        }
+        None => {
+            return Err(error(
+                ErrorCode::InvalidDirective,
+                format!("invalid value `{}` for constant `{}`", value, name),
+                value_span.clone(),
+            )
+            .with_hint("expected a number, e.g. `16384`"))
+        }
+    };
+    Ok(Instruction::Constant(name.to_string(), value))
+}
+
+#[allow(clippy::result_large_err)]
+fn parse_c_instruction(
+    instruction: &str,
+    line_number: usize,
+    options: &Options,
+    warnings: &mut Vec<AsmError>,
+) -> Result<Instruction, AsmError> {
     let parts: Vec<&str> = instruction.split(';').collect();
+    if parts.len() > 2 {
+        let start = parts[0].len() + parts[1].len() + 1;
+        return Err(AsmError::error(
+            ErrorCode::InvalidInstruction,
+            line_number,
+            format!("unexpected `{}` after the jump", &instruction[start..]),
+        )
+        .with_span(start..instruction.len())
+        .with_hint("a C-instruction can only have one `;`"));
+    }
     let comp_dest = parts[0];
     let jump = parts.get(1).unwrap_or(&"null");
+    let jump_start = comp_dest.len() + 1;
 
     let comp_dest_parts: Vec<&str> = comp_dest.split('=').collect();
-    let (dest, comp) = if comp_dest_parts.len() == 2 {
-        (comp_dest_parts[0], comp_dest_parts[1])
+    let (dest, comp, comp_start) = if comp_dest_parts.len() == 2 {
+        (comp_dest_parts[0], comp_dest_parts[1], comp_dest_parts[0].len() + 1)
     } else if comp_dest_parts.len() == 1 {
-        ("null", comp_dest_parts[0])
+        ("null", comp_dest_parts[0], 0)
     } else {
-        return Err(AsmError::InvalidInstruction {
-            line: line_number,
-            instruction: instruction.to_string(),
-        });
+        return Err(AsmError::error(
+            ErrorCode::InvalidInstruction,
+            line_number,
+            "a C-instruction can only have one `=`",
+        )
+        .with_span(0..comp_dest.len()));
     };
 
-    let dest_enum = Dest::try_from((dest, line_number)).unwrap_or(Dest::Null);
-    let comp_enum = Comp::try_from((comp, line_number))?;
-    let jump_enum = Jump::try_from((*jump, line_number)).unwrap_or(Jump::Null);
+    // Parts may contain whitespace (`D = D + 1`), so spans are taken from the
+    // original text rather than from the normalized mnemonic.
+    let dest_span = trimmed_span(dest, 0);
+    let comp_span = trimmed_span(comp, comp_start);
+    let jump_span = trimmed_span(jump, jump_start);
+    let dest = normalize(dest, dest_span.clone(), line_number, canonical_dest, options, warnings);
+    let comp = normalize(comp, comp_span.clone(), line_number, canonical_comp, options, warnings);
+    let jump: String = jump.split_whitespace().collect();
+
+    let dest_enum = or_null(
+        Dest::try_from((dest.as_str(), line_number)).map_err(|e| e.with_span(dest_span)),
+        Dest::Null,
+        options,
+        warnings,
+    )?;
+    let comp_enum = Comp::try_from((comp.as_str(), line_number)).map_err(|e| e.with_span(comp_span))?;
+    let jump_enum = or_null(
+        Jump::try_from((jump.as_str(), line_number)).map_err(|e| e.with_span(jump_span)),
+        Jump::Null,
+        options,
+        warnings,
+    )?;
 
     Ok(Instruction::C(CInstruction::new(
         dest_enum, comp_enum, jump_enum,
     )))
 }
 
+fn trimmed_span(part: &str, start: usize) -> Range<usize> {
+    let leading = part.len() - part.trim_start().len();
+    start + leading..start + part.trim_end().len()
+}
+
+// Strips whitespace from a dest or comp and rewrites equivalent spellings
+// accepted by other Hack toolchains to the canonical mnemonic.
+fn normalize(
+    part: &str,
+    span: Range<usize>,
+    line_number: usize,
+    canonical: fn(&str) -> Option<String>,
+    options: &Options,
+    warnings: &mut Vec<AsmError>,
+) -> String {
+    let compact: String = part.split_whitespace().collect();
+    match canonical(&compact) {
+        Some(canonical) if canonical != compact => {
+            if options.warn_noncanonical {
+                warnings.push(
+                    AsmError::warning(
+                        ErrorCode::NonCanonical,
+                        line_number,
+                        format!("`{}` is not the canonical spelling", compact),
+                    )
+                    .with_span(span)
+                    .with_hint(format!("write it as `{}`", canonical)),
+                );
+            }
+            canonical
+        }
+        _ => compact,
+    }
+}
+
+// Any ordering of distinct A, M and D registers, e.g. `DM` for `MD`.
+fn canonical_dest(dest: &str) -> Option<String> {
+    if dest.is_empty() || !dest.chars().all(|c| matches!(c, 'A' | 'M' | 'D')) {
+        return None;
+    }
+    let canonical: String = ['A', 'M', 'D']
+        .into_iter()
+        .filter(|register| dest.contains(*register))
+        .collect();
+    (canonical.len() == dest.len()).then_some(canonical)
+}
+
+// Commuted forms of the symmetric operations, e.g. `A+D`, `M|D` or `1+D`.
+fn canonical_comp(comp: &str) -> Option<String> {
+    for op in ['+', '&', '|'] {
+        if let Some((left, right)) = comp.split_once(op) {
+            let swap = matches!((left, right), ("A" | "M", "D"))
+                || (op == '+' && left == "1" && matches!(right, "D" | "A" | "M"));
+            if swap {
+                return Some(format!("{}{}{}", right, op, left));
+            }
+        }
+    }
+    None
+}
+
+// Legacy toolchains silently treated an unknown dest or jump as null. Lenient
+// mode keeps that behaviour but reports it as a warning.
+#[allow(clippy::result_large_err)]
+fn or_null<T>(
+    result: Result<T, AsmError>,
+    null: T,
+    options: &Options,
+    warnings: &mut Vec<AsmError>,
+) -> Result<T, AsmError> {
+    match result {
+        Err(e) if !options.strict => {
+            let mut warning = e.into_warning();
+            warning.message.push_str(", assembling it as null");
+            warnings.push(warning);
+            Ok(null)
+        }
+        result => result,
+    }
+}
+
+// A-instructions have a 15-bit payload; the top bit marks C-instructions.
+pub const MAX_A_VALUE: u16 = 0x7FFF;
+
 #[derive(Debug, Clone)]
 pub struct AInstruction {
     value: u16,
@@ -109,7 +489,17 @@ impl CInstruction {
     }
 }
 
-#[derive(Debug, Clone)]
+fn with_suggestion<'a, I>(error: AsmError, input: &str, mnemonics: I) -> AsmError
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    match closest(input, mnemonics.into_iter().filter(|m| *m != "null")) {
+        Some(suggestion) => error.with_hint(format!("did you mean `{}`?", suggestion)),
+        None => error,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum Dest {
     Null = 0b000,
@@ -122,6 +512,36 @@ pub enum Dest {
     AMD = 0b111,
 }
 
+impl Dest {
+    pub(crate) const ALL: [Dest; 8] = [
+        Dest::Null,
+        Dest::M,
+        Dest::D,
+        Dest::MD,
+        Dest::A,
+        Dest::AM,
+        Dest::AD,
+        Dest::AMD,
+    ];
+
+    pub fn from_bits(bits: u16) -> Self {
+        Self::ALL[(bits & 0b111) as usize]
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Dest::Null => "null",
+            Dest::M => "M",
+            Dest::D => "D",
+            Dest::MD => "MD",
+            Dest::A => "A",
+            Dest::AM => "AM",
+            Dest::AD => "AD",
+            Dest::AMD => "AMD",
+        }
+    }
+}
+
 impl TryFrom<(&str, usize)> for Dest {
     type Error = AsmError;
 
@@ -136,15 +556,22 @@ impl TryFrom<(&str, usize)> for Dest {
             "AM" => Ok(Dest::AM),
             "AD" => Ok(Dest::AD),
             "AMD" => Ok(Dest::AMD),
-            _ => Err(AsmError::InvalidInstruction {
-                line,
-                instruction: input.to_string(),
-            }),
+            "" => Err(AsmError::error(ErrorCode::InvalidDest, line, "missing destination before `=`")),
+            _ => Err(with_suggestion(
+                AsmError::error(
+                    ErrorCode::InvalidDest,
+                    line,
+                    format!("invalid destination `{}`", input),
+                )
+                .with_span(0..input.len()),
+                input,
+                Dest::ALL.iter().map(Dest::mnemonic),
+            )),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum Comp {
     Zero = 0b0101010,
@@ -178,6 +605,75 @@ pub enum Comp {
 }
 
 impl Comp {
+    pub(crate) const ALL: [Comp; 28] = [
+        Comp::Zero,
+        Comp::One,
+        Comp::NegOne,
+        Comp::D,
+        Comp::A,
+        Comp::NotD,
+        Comp::NotA,
+        Comp::NegD,
+        Comp::NegA,
+        Comp::DPlusOne,
+        Comp::APlusOne,
+        Comp::DMinusOne,
+        Comp::AMinusOne,
+        Comp::DPlusA,
+        Comp::DMinusA,
+        Comp::AMinusD,
+        Comp::DAndA,
+        Comp::DOrA,
+        Comp::M,
+        Comp::NotM,
+        Comp::NegM,
+        Comp::MPlusOne,
+        Comp::MMinusOne,
+        Comp::DPlusM,
+        Comp::DMinusM,
+        Comp::MMinusD,
+        Comp::DAndM,
+        Comp::DOrM,
+    ];
+
+    // Takes the 7-bit 'a' + 'c1..c6' field of a C-instruction.
+    pub fn from_bits(bits: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|comp| *comp as u16 == bits)
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Comp::Zero => "0",
+            Comp::One => "1",
+            Comp::NegOne => "-1",
+            Comp::D => "D",
+            Comp::A => "A",
+            Comp::NotD => "!D",
+            Comp::NotA => "!A",
+            Comp::NegD => "-D",
+            Comp::NegA => "-A",
+            Comp::DPlusOne => "D+1",
+            Comp::APlusOne => "A+1",
+            Comp::DMinusOne => "D-1",
+            Comp::AMinusOne => "A-1",
+            Comp::DPlusA => "D+A",
+            Comp::DMinusA => "D-A",
+            Comp::AMinusD => "A-D",
+            Comp::DAndA => "D&A",
+            Comp::DOrA => "D|A",
+            Comp::M => "M",
+            Comp::NotM => "!M",
+            Comp::NegM => "-M",
+            Comp::MPlusOne => "M+1",
+            Comp::MMinusOne => "M-1",
+            Comp::DPlusM => "D+M",
+            Comp::DMinusM => "D-M",
+            Comp::MMinusD => "M-D",
+            Comp::DAndM => "D&M",
+            Comp::DOrM => "D|M",
+        }
+    }
+
     pub fn convert_to_binary(self) -> u16 {
         self as u16 & 0b0111111 // Strip the 'a-bit', return only the 6-bit computation code
     }
@@ -220,15 +716,23 @@ impl TryFrom<(&str, usize)> for Comp {
             "M-D" => Ok(Comp::MMinusD),
             "D&M" => Ok(Comp::DAndM),
             "D|M" => Ok(Comp::DOrM),
-            _ => Err(AsmError::InvalidInstruction {
-                line,
-                instruction: input.to_string(),
-            }),
+            "" => Err(AsmError::error(ErrorCode::InvalidComp, line, "missing computation")
+                .with_hint("a C-instruction needs a computation, e.g. `D=M` or `0;JMP`")),
+            _ => Err(with_suggestion(
+                AsmError::error(
+                    ErrorCode::InvalidComp,
+                    line,
+                    format!("invalid computation `{}`", input),
+                )
+                .with_span(0..input.len()),
+                input,
+                Comp::ALL.iter().map(Comp::mnemonic),
+            )),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum Jump {
     Null = 0b000,
@@ -241,6 +745,36 @@ pub enum Jump {
     JMP = 0b111,
 }
 
+impl Jump {
+    pub(crate) const ALL: [Jump; 8] = [
+        Jump::Null,
+        Jump::JGT,
+        Jump::JEQ,
+        Jump::JGE,
+        Jump::JLT,
+        Jump::JNE,
+        Jump::JLE,
+        Jump::JMP,
+    ];
+
+    pub fn from_bits(bits: u16) -> Self {
+        Self::ALL[(bits & 0b111) as usize]
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Jump::Null => "null",
+            Jump::JGT => "JGT",
+            Jump::JEQ => "JEQ",
+            Jump::JGE => "JGE",
+            Jump::JLT => "JLT",
+            Jump::JNE => "JNE",
+            Jump::JLE => "JLE",
+            Jump::JMP => "JMP",
+        }
+    }
+}
+
 impl TryFrom<(&str, usize)> for Jump {
     type Error = AsmError;
 
@@ -255,10 +789,177 @@ impl TryFrom<(&str, usize)> for Jump {
             "JNE" => Ok(Jump::JNE),
             "JLE" => Ok(Jump::JLE),
             "JMP" => Ok(Jump::JMP),
-            _ => Err(AsmError::InvalidInstruction {
-                line,
-                instruction: input.to_string(),
-            }),
+            "" => Err(AsmError::error(ErrorCode::InvalidJump, line, "missing jump after `;`")),
+            _ => Err(with_suggestion(
+                AsmError::error(
+                    ErrorCode::InvalidJump,
+                    line,
+                    format!("invalid jump `{}`", input),
+                )
+                .with_span(0..input.len()),
+                input,
+                Jump::ALL.iter().map(Jump::mnemonic),
+            )),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::Severity;
+
+    #[test]
+    fn test_error_spans() {
+        let error = Instruction::parse("  D=M+Q;JMP", 3).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidComp);
+        assert_eq!(error.line, 3);
+        assert_eq!(error.span, 4..7);
+
+        let error = Instruction::parse("@", 1).unwrap_err();
+        assert_eq!(error.code, ErrorCode::MissingAddress);
+        assert_eq!(error.span, 0..1);
+        assert!(error.hint.is_some());
+    }
+
+    #[test]
+    fn test_strict_dest_and_jump() {
+        let error = Instruction::parse("0;JUMP", 7).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidJump);
+        assert_eq!(error.span, 2..6);
+        assert_eq!(error.hint.as_deref(), Some("did you mean `JMP`?"));
+
+        let error = Instruction::parse("DX=1", 7).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidDest);
+        assert_eq!(error.span, 0..2);
+    }
+
+    #[test]
+    fn test_lenient_dest_and_jump() {
+        let options = Options {
+            strict: false,
+            ..Options::default()
+        };
+        let mut warnings = Vec::new();
+        let instruction = Instruction::parse_with("DX=1;JUMP", 7, &options, &mut warnings).unwrap();
+
+        let Instruction::C(c_instruction) = instruction else {
+            panic!("expected a C-instruction");
+        };
+        assert_eq!(c_instruction.to_string(), "1");
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings.iter().all(|w| w.severity == Severity::Warning));
+    }
+
+    #[test]
+    fn test_alternate_spellings() {
+        let cases = [
+            ("DM=M+1", "MD=M+1"),
+            ("DMA = A + D", "AMD=D+A"),
+            ("D = D + 1", "D=D+1"),
+            ("M=M|D", "M=D|M"),
+            ("A=A&D", "A=D&A"),
+            ("D=1+M ; JGT", "D=M+1;JGT"),
+        ];
+        for (source, expected) in cases {
+            let Ok(Instruction::C(c_instruction)) = Instruction::parse(source, 1) else {
+                panic!("failed to parse {source}");
+            };
+            assert_eq!(c_instruction.to_string(), expected);
+        }
+
+        assert!(Instruction::parse("MM=1", 1).is_err());
+        assert!(Instruction::parse("D=A-D", 1).is_ok());
+        assert!(Instruction::parse("D=D-A", 1).is_ok());
+    }
+
+    #[test]
+    fn test_noncanonical_warning() {
+        let options = Options {
+            warn_noncanonical: true,
+            ..Options::default()
+        };
+        let mut warnings = Vec::new();
+        Instruction::parse_with("  DM = A + D", 1, &options, &mut warnings).unwrap();
+
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].code, ErrorCode::NonCanonical);
+        assert_eq!(warnings[0].span, 2..4);
+        assert_eq!(warnings[1].span, 7..12);
+        assert_eq!(warnings[1].hint.as_deref(), Some("write it as `D+A`"));
+    }
+
+    #[test]
+    fn test_constants() {
+        for source in [".equ ROWS 32", ".equ ROWS, 32", ".define ROWS 32"] {
+            match Instruction::parse(source, 1).unwrap() {
+                Instruction::Constant(name, value) => assert_eq!((name.as_str(), value), ("ROWS", 32)),
+                other => panic!("{} parsed as {:?}", source, other),
+            }
+        }
+
+        let error = Instruction::parse(".equ BIG 32768", 1).unwrap_err();
+        assert_eq!(error.code, ErrorCode::ValueOutOfRange);
+        assert_eq!(error.span, 9..14);
+        let error = Instruction::parse(".equ ROWS", 1).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidDirective);
+        let error = Instruction::parse(".equ 2X 1", 1).unwrap_err();
+        assert_eq!(error.span, 5..7);
+        // Spans are the reported token's, not a later one with the same text.
+        let error = Instruction::parse(".equ 1 1", 1).unwrap_err();
+        assert_eq!(error.span, 5..6);
+        let error = Instruction::parse(".equ N, 5 1 1", 1).unwrap_err();
+        assert_eq!((error.message.as_str(), error.span), ("unexpected `1 1` after the value", 10..13));
+    }
+
+    #[test]
+    fn test_a_instruction_range() {
+        assert!(matches!(Instruction::parse("@32767", 1), Ok(Instruction::A(a)) if a.value() == 32767));
+
+        for source in ["@32768", "@40000", "@-1", "@-5", "@0x8000", "@1+32767", "@99999999999999999999"] {
+            let error = Instruction::parse(source, 1).unwrap_err();
+            assert_eq!(error.code, ErrorCode::ValueOutOfRange, "{}", source);
+            assert_eq!(error.span, 1..source.len(), "{}", source);
+        }
+        let error = Instruction::parse("@-5", 1).unwrap_err();
+        assert_eq!(error.hint.as_deref(), Some("A-instructions can't be negative; load `@5` and negate it with `D=-A`"));
+
+        // Expressions are checked again when encoded, keeping the whole error.
+        let mut symbol_table = SymbolTable::default();
+        symbol_table.add_constant("N".to_string(), 32767);
+        let error = Instruction::parse("@N+1", 1).unwrap().encode(&mut symbol_table, 7).unwrap_err();
+        let error = error.downcast::<AsmError>().unwrap();
+        assert_eq!((error.code, error.line, error.span.clone()), (ErrorCode::ValueOutOfRange, 7, 1..4));
+    }
+
+    #[test]
+    fn test_label_grammar() {
+        for source in ["(LOOP)", "(ball.setdestination$if_true0)", "(a:b_c)"] {
+            assert!(matches!(Instruction::parse(source, 1), Ok(Instruction::L(_))), "{}", source);
+        }
+
+        let cases = [
+            ("(foo bar)", 5..8, "unexpected `bar` in label `foo`"),
+            ("()", 0..2, "empty label"),
+            ("((X))", 1..2, "unexpected `(` in label"),
+            ("(1abc)", 1..5, "label `1abc` starts with a digit"),
+            ("(END", 0..4, "missing `)` after label `END`"),
+            ("(END) 0;JMP", 6..11, "unexpected `0;JMP` after label"),
+        ];
+        for (source, span, message) in cases {
+            let error = Instruction::parse(source, 1).unwrap_err();
+            assert_eq!(error.code, ErrorCode::InvalidLabel, "{}", source);
+            assert_eq!((error.span, error.message.as_str()), (span, message), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_trailing_garbage() {
+        let error = Instruction::parse("@1abc", 1).unwrap_err();
+        assert_eq!((error.code, error.span), (ErrorCode::InvalidExpression, 1..5));
+        let error = Instruction::parse("@LOOP x", 1).unwrap_err();
+        assert_eq!(error.span, 6..7);
+        let error = Instruction::parse("0;JMP;JGT", 1).unwrap_err();
+        assert_eq!((error.code, error.span), (ErrorCode::InvalidInstruction, 5..9));
+    }
+}
@@ -0,0 +1,88 @@
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    // A run of symbol characters that doesn't start with a digit.
+    Symbol,
+    // A run of symbol characters that starts with a digit; not necessarily a
+    // valid number, so `1abc` is a single token.
+    Number,
+    // Any other character: operators, parentheses and stray punctuation.
+    Punct(char),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    pub span: Range<usize>,
+}
+
+pub fn is_symbol_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$' | ':')
+}
+
+// Hack symbols: letters, digits, `_`, `.`, `$` and `:`, not starting with a digit.
+pub fn is_symbol(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()) && name.chars().all(is_symbol_char)
+}
+
+// Splits `text` into tokens, skipping whitespace. Spans are byte ranges into
+// `text`.
+pub fn tokenize(text: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        let kind = if is_symbol_char(c) {
+            while let Some(&(index, next)) = chars.peek() {
+                if !is_symbol_char(next) {
+                    break;
+                }
+                end = index + next.len_utf8();
+                chars.next();
+            }
+            if c.is_ascii_digit() {
+                TokenKind::Number
+            } else {
+                TokenKind::Symbol
+            }
+        } else {
+            TokenKind::Punct(c)
+        };
+        tokens.push(Token {
+            kind,
+            text: &text[start..end],
+            span: start..end,
+        });
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        let tokens: Vec<(TokenKind, &str, Range<usize>)> = tokenize("(ball.x$1 + 0x1F)*2ab")
+            .into_iter()
+            .map(|token| (token.kind, token.text, token.span))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (TokenKind::Punct('('), "(", 0..1),
+                (TokenKind::Symbol, "ball.x$1", 1..9),
+                (TokenKind::Punct('+'), "+", 10..11),
+                (TokenKind::Number, "0x1F", 12..16),
+                (TokenKind::Punct(')'), ")", 16..17),
+                (TokenKind::Punct('*'), "*", 17..18),
+                (TokenKind::Number, "2ab", 18..21),
+            ]
+        );
+    }
+}
@@ -1,68 +1,802 @@
 mod assembler;
+mod debugger;
+mod disassembler;
+mod emulator;
 mod error;
+mod expression;
+mod formatter;
 mod instruction;
+mod json;
+mod lexer;
+mod lint;
+mod lsp;
+mod optimizer;
+mod output;
+mod preprocessor;
+mod script;
+mod suggest;
 mod symbol_table;
+mod translator;
 
-use clap::{arg, Command};
+use clap::{arg, ArgMatches, Command};
 
 use std::{
     fs::File,
-    io::{BufReader, BufWriter},
-    path::PathBuf,
+    io::{BufRead, BufReader, BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
 };
 
 use crate::error::AssemblyError;
-pub use error::AsmError;
-pub use symbol_table::SymbolTable;
+pub use assembler::Program;
+pub use debugger::Debugger;
+pub use emulator::{Emulator, Halt};
+pub use error::{AsmError, Diagnostics, ErrorCode, Severity};
+pub use lint::{Lint, LintLevel, LintLevels};
+pub use lsp::LanguageServer;
+pub use output::OutputFormat;
+pub use script::{run_script, Mismatch, ScriptResult};
+pub use symbol_table::{Symbol, SymbolKind, SymbolTable};
+pub use translator::Translator;
 
 pub type Error = Box<dyn std::error::Error>;
 pub type Result<T> = std::result::Result<T, Error>;
 
 
+pub enum Mode {
+    Assemble,
+    Disassemble,
+    Run { max_steps: usize, dump: Range<u16> },
+    Lint(LintLevels),
+    Format { check: bool, indent: usize },
+    // Translates VM code; `asm` stops at the assembly instead of assembling it.
+    Vm { asm: bool },
+    // Runs a .tst script; `max_steps` bounds `repeat` loops without a count.
+    Test { max_steps: usize },
+    // Starts the interactive debugger; `max_steps` bounds each `continue`.
+    Debug { max_steps: usize },
+}
+
+#[derive(Clone, Copy)]
+pub enum SymbolFormat {
+    Sym,
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    // Reject unknown dest and jump mnemonics instead of assembling them as null.
+    pub strict: bool,
+    // Warn about accepted but non-canonical spellings such as `DM` or `A+D`.
+    pub warn_noncanonical: bool,
+    // Directories searched for `.include`d files after the including file's own.
+    pub include_paths: Vec<PathBuf>,
+    // Run the peephole optimizer before encoding.
+    pub optimize: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            strict: true,
+            warn_noncanonical: false,
+            include_paths: Vec::new(),
+            optimize: false,
+        }
+    }
+}
+
+// Passing `-` as the input or output file reads from stdin or writes to stdout.
+pub const STDIO: &str = "-";
+
 pub struct Config {
     pub input_file: PathBuf,
     pub output_file: PathBuf,
+    pub listing_file: Option<PathBuf>,
+    pub symbols: Option<SymbolFormat>,
+    pub format: OutputFormat,
+    pub options: Options,
+    pub mode: Mode,
 }
 
-pub fn match_args() -> Result<Config> {
+pub fn match_args() -> Result<Vec<Config>> {
     let matches = Command::new("Hack Assembler")
         .version("1.0")
         .author("d-holguin")
         .about("This is a assembler for the Hack computer, part of the Nand2Tetris course")
-        .arg(arg!(-f --file [FILE] "Sets the input file").value_hint(clap::ValueHint::FilePath))
+        .arg(
+            arg!(-f --file [FILE]... "Sets the input file(s); a directory assembles every .asm file in it")
+                .num_args(1..)
+                .value_hint(clap::ValueHint::AnyPath),
+        )
+        .arg(
+            arg!(-o --output <FILE> "Sets the output file, or - for stdout; defaults to the input with the format's extension")
+                .value_hint(clap::ValueHint::FilePath),
+        )
+        .arg(
+            arg!(-F --format <FORMAT> "Sets the output format")
+                .value_parser(OutputFormat::NAMES)
+                .default_value("hack"),
+        )
+        .arg(arg!(-l --listing "Also writes a .lst listing next to the output"))
+        .arg(
+            arg!(-s --symbols <FORMAT> "Exports the symbol table to a .sym file or prints it as JSON")
+                .value_parser(["sym", "json"]),
+        )
+        .arg(arg!(--lenient "Assembles unknown dest and jump mnemonics as null, with a warning"))
+        .arg(arg!(--"warn-noncanonical" "Warns about alternate spellings such as DM=A+D"))
+        .arg(include_arg())
+        .arg(arg!(-O --optimize "Removes redundant instructions, such as repeated loads and dead code"))
         .hide_possible_values(false)
+        .subcommand(
+            Command::new("disasm")
+                .about("Disassembles a .hack file back into Hack assembly")
+                .arg(
+                    arg!(-f --file [FILE]... "Sets the input .hack file(s) or a directory of them")
+                        .num_args(1..)
+                        .value_hint(clap::ValueHint::AnyPath),
+                )
+                .arg(
+                    arg!(-o --output <FILE> "Sets the output file, or - for stdout")
+                        .value_hint(clap::ValueHint::FilePath),
+                ),
+        )
+        .subcommand(
+            Command::new("run")
+                .about("Runs a .asm or .hack program on the built-in Hack CPU emulator")
+                .arg(arg!(-f --file [FILE] "Sets the program to run").value_hint(clap::ValueHint::FilePath))
+                .arg(
+                    arg!(--steps <STEPS> "Stops after this many instructions")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("1000000"),
+                )
+                .arg(arg!(--dump <RANGE> "RAM addresses to print when the program stops, e.g. 0..16").default_value("0..16"))
+                .arg(include_arg()),
+        )
+        .subcommand(
+            Command::new("lint")
+                .about("Checks .asm files for common mistakes without writing any output")
+                .arg(
+                    arg!(-f --file [FILE]... "Sets the input .asm file(s) or a directory of them")
+                        .num_args(1..)
+                        .value_hint(clap::ValueHint::AnyPath),
+                )
+                .arg(lint_arg(arg!(-A --allow <LINT> "Turns a lint off")))
+                .arg(lint_arg(arg!(-W --warn <LINT> "Reports a lint as a warning")))
+                .arg(lint_arg(arg!(-D --deny <LINT> "Reports a lint as an error, failing the run, even if the source allows it")))
+                .arg(include_arg()),
+        )
+        .subcommand(
+            Command::new("fmt")
+                .about("Rewrites .asm files in the canonical style")
+                .arg(
+                    arg!(-f --file [FILE]... "Sets the .asm file(s) or a directory of them; - prints stdin formatted")
+                        .num_args(1..)
+                        .value_hint(clap::ValueHint::AnyPath),
+                )
+                .arg(arg!(--check "Lists files that aren't formatted instead of rewriting them"))
+                .arg(
+                    arg!(--indent <SPACES> "Indents instructions by this many spaces")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("4"),
+                ),
+        )
+        .subcommand(
+            Command::new("vm")
+                .about("Translates VM code to Hack assembly and assembles it")
+                .arg(
+                    arg!(-f --file [FILE]... "Sets the .vm file, or a directory whose .vm files form one program")
+                        .num_args(1..)
+                        .value_hint(clap::ValueHint::AnyPath),
+                )
+                .arg(
+                    arg!(-o --output <FILE> "Sets the output file, or - for stdout")
+                        .value_hint(clap::ValueHint::FilePath),
+                )
+                .arg(arg!(--asm "Writes the translated assembly instead of assembling it"))
+                .arg(arg!(-O --optimize "Removes redundant instructions from the assembled program")),
+        )
+        .subcommand(
+            Command::new("test")
+                .about("Runs Nand2Tetris .tst scripts and compares their output with the .cmp files")
+                .arg(
+                    arg!(-f --file [FILE]... "Sets the .tst script(s) or a directory of them")
+                        .num_args(1..)
+                        .value_hint(clap::ValueHint::AnyPath),
+                )
+                .arg(
+                    arg!(--steps <STEPS> "Stops a `repeat` without a count after this many instructions")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("1000000"),
+                ),
+        )
+        .subcommand(
+            Command::new("debug")
+                .about("Debugs a .asm or .hack program interactively, with breakpoints and watchpoints")
+                .arg(arg!(-f --file [FILE] "Sets the program to debug").value_hint(clap::ValueHint::FilePath))
+                .arg(
+                    arg!([INPUT] "The program to debug, as an alternative to --file")
+                        .conflicts_with("file")
+                        .value_hint(clap::ValueHint::FilePath),
+                )
+                .arg(
+                    arg!(--steps <STEPS> "Stops a `continue`, `step` or `next` after this many instructions")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("1000000"),
+                )
+                .arg(include_arg()),
+        )
         .get_matches();
 
-    let input_file = matches
-        .get_one::<String>("file")
-        .ok_or("No input file provided")?;
+    match matches.subcommand() {
+        Some(("disasm", sub_matches)) => input_files(sub_matches, "hack")?
+            .into_iter()
+            .map(|input_file| {
+                Ok(Config {
+                    output_file: output_file(sub_matches, &input_file, "dis.asm"),
+                    input_file,
+                    listing_file: None,
+                    symbols: None,
+                    format: OutputFormat::Hack,
+                    options: Options::default(),
+                    mode: Mode::Disassemble,
+                })
+            })
+            .collect(),
+        Some(("lint", sub_matches)) => {
+            let mut levels = LintLevels::new();
+            for (id, level) in [("allow", LintLevel::Allow), ("warn", LintLevel::Warn), ("deny", LintLevel::Deny)] {
+                for name in sub_matches.get_many::<String>(id).into_iter().flatten() {
+                    levels.set(Lint::from_name(name).ok_or("Unknown lint")?, level);
+                }
+            }
+            let options = Options {
+                include_paths: include_paths(sub_matches),
+                ..Options::default()
+            };
+            input_files(sub_matches, "asm")?
+                .into_iter()
+                .map(|input_file| {
+                    Ok(Config {
+                        output_file: input_file.clone(),
+                        input_file,
+                        listing_file: None,
+                        symbols: None,
+                        format: OutputFormat::Hack,
+                        options: options.clone(),
+                        mode: Mode::Lint(levels.clone()),
+                    })
+                })
+                .collect()
+        }
+        Some(("fmt", sub_matches)) => {
+            let check = sub_matches.get_flag("check");
+            let indent = *sub_matches.get_one::<usize>("indent").unwrap_or(&4);
+            input_files(sub_matches, "asm")?
+                .into_iter()
+                .map(|input_file| {
+                    Ok(Config {
+                        output_file: input_file.clone(),
+                        input_file,
+                        listing_file: None,
+                        symbols: None,
+                        format: OutputFormat::Hack,
+                        options: Options::default(),
+                        mode: Mode::Format { check, indent },
+                    })
+                })
+                .collect()
+        }
+        Some(("vm", sub_matches)) => {
+            let paths: Vec<PathBuf> = sub_matches
+                .get_many::<String>("file")
+                .ok_or("No input file provided")?
+                .map(PathBuf::from)
+                .collect();
+            if sub_matches.contains_id("output") && paths.len() > 1 {
+                return Err("--output can only be used with a single input file".into());
+            }
+            let asm = sub_matches.get_flag("asm");
+            let extension = if asm { "asm" } else { "hack" };
+            paths
+                .into_iter()
+                .map(|input_file| {
+                    if !input_file.is_dir() && input_file.extension().unwrap_or_default() != "vm" {
+                        return Err("The provided file must have a .vm extension or be a directory".into());
+                    }
+                    // A directory `Prog` becomes `Prog/Prog.hack`, as in Nand2Tetris.
+                    let default_output = match input_file.is_dir() {
+                        true => input_file.join(input_file.file_name().unwrap_or_default()),
+                        false => input_file.clone(),
+                    };
+                    Ok(Config {
+                        output_file: sub_matches
+                            .get_one::<String>("output")
+                            .map(PathBuf::from)
+                            .unwrap_or_else(|| default_output.with_extension(extension)),
+                        input_file,
+                        listing_file: None,
+                        symbols: None,
+                        format: OutputFormat::Hack,
+                        options: Options {
+                            optimize: sub_matches.get_flag("optimize"),
+                            ..Options::default()
+                        },
+                        mode: Mode::Vm { asm },
+                    })
+                })
+                .collect()
+        }
+        Some(("test", sub_matches)) => {
+            let max_steps = *sub_matches.get_one::<usize>("steps").unwrap_or(&1_000_000);
+            input_files(sub_matches, "tst")?
+                .into_iter()
+                .map(|input_file| {
+                    if is_stdio(&input_file) {
+                        return Err("Test scripts must be files, since they name files next to them".into());
+                    }
+                    Ok(Config {
+                        output_file: input_file.with_extension("out"),
+                        input_file,
+                        listing_file: None,
+                        symbols: None,
+                        format: OutputFormat::Hack,
+                        options: Options::default(),
+                        mode: Mode::Test { max_steps },
+                    })
+                })
+                .collect()
+        }
+        Some(("debug", sub_matches)) => {
+            let input_file = sub_matches
+                .get_one::<String>("file")
+                .or_else(|| sub_matches.get_one::<String>("INPUT"))
+                .map(PathBuf::from)
+                .ok_or("No input file provided")?;
+            let max_steps = *sub_matches.get_one::<usize>("steps").unwrap_or(&1_000_000);
+            Ok(vec![Config {
+                output_file: input_file.clone(),
+                input_file,
+                listing_file: None,
+                symbols: None,
+                format: OutputFormat::Hack,
+                options: Options {
+                    include_paths: include_paths(sub_matches),
+                    ..Options::default()
+                },
+                mode: Mode::Debug { max_steps },
+            }])
+        }
+        Some(("run", sub_matches)) => {
+            let input_file = sub_matches
+                .get_one::<String>("file")
+                .map(PathBuf::from)
+                .ok_or("No input file provided")?;
+            let max_steps = *sub_matches.get_one::<usize>("steps").unwrap_or(&1_000_000);
+            let dump = parse_range(sub_matches.get_one::<String>("dump").map_or("0..16", |s| s))?;
+            Ok(vec![Config {
+                output_file: input_file.clone(),
+                input_file,
+                listing_file: None,
+                symbols: None,
+                format: OutputFormat::Hack,
+                options: Options {
+                    include_paths: include_paths(sub_matches),
+                    ..Options::default()
+                },
+                mode: Mode::Run { max_steps, dump },
+            }])
+        }
+        _ => {
+            let symbols = match matches.get_one::<String>("symbols").map(String::as_str) {
+                Some("sym") => Some(SymbolFormat::Sym),
+                Some("json") => Some(SymbolFormat::Json),
+                _ => None,
+            };
+            let options = Options {
+                strict: !matches.get_flag("lenient"),
+                warn_noncanonical: matches.get_flag("warn-noncanonical"),
+                include_paths: include_paths(&matches),
+                optimize: matches.get_flag("optimize"),
+            };
+            let format = matches
+                .get_one::<String>("format")
+                .and_then(|name| OutputFormat::from_name(name))
+                .unwrap_or(OutputFormat::Hack);
+            let listing = matches.get_flag("listing");
+            input_files(&matches, "asm")?
+                .into_iter()
+                .map(|input_file| {
+                    let output_file = output_file(&matches, &input_file, format.extension());
+                    if is_stdio(&output_file) && matches!(symbols, Some(SymbolFormat::Json)) {
+                        return Err("Can't print the symbol table as JSON while writing the program to stdout".into());
+                    }
+                    Ok(Config {
+                        listing_file: listing
+                            .then(|| sidecar_file(&input_file, &output_file, "lst"))
+                            .transpose()?,
+                        output_file,
+                        input_file,
+                        symbols,
+                        format,
+                        options: options.clone(),
+                        mode: Mode::Assemble,
+                    })
+                })
+                .collect()
+        }
+    }
+}
 
-    let input_file = PathBuf::from(&input_file);
+// Expands the `--file` values into input files. Directories contribute every
+// file in them with the given extension, in name order.
+fn input_files(matches: &ArgMatches, extension: &str) -> Result<Vec<PathBuf>> {
+    let paths: Vec<PathBuf> = matches
+        .get_many::<String>("file")
+        .ok_or("No input file provided")?
+        .map(PathBuf::from)
+        .collect();
+    // `lint` has no `--output`.
+    let has_output = matches.try_contains_id("output").unwrap_or(false);
+    if has_output && paths.len() > 1 {
+        return Err("--output can only be used with a single input file".into());
+    }
 
-    if input_file.extension().unwrap_or_default() != "asm" {
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "The provided file must have an .asm extension",
-        )));
+    let mut input_files = Vec::new();
+    for path in paths {
+        if is_stdio(&path) {
+            input_files.push(path);
+        } else if path.is_dir() {
+            let mut entries: Vec<PathBuf> = std::fs::read_dir(&path)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<_>>()?;
+            entries.retain(|entry| entry.is_file() && entry.extension().unwrap_or_default() == extension);
+            if entries.is_empty() {
+                return Err(format!("No .{} files found in {}", extension, path.display()).into());
+            }
+            if has_output && entries.len() > 1 {
+                return Err("--output can only be used with a single input file".into());
+            }
+            entries.sort();
+            input_files.extend(entries);
+        } else if path.extension().unwrap_or_default() != extension {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("The provided file must have an .{} extension", extension),
+            )));
+        } else {
+            input_files.push(path);
+        }
     }
-    let output_file = input_file.with_extension("hack");
+    Ok(input_files)
+}
+
+fn include_arg() -> clap::Arg {
+    arg!(-I --include <DIR> "Adds a directory to search for .include files; may be repeated")
+        .action(clap::ArgAction::Append)
+        .value_hint(clap::ValueHint::DirPath)
+}
+
+fn lint_arg(arg: clap::Arg) -> clap::Arg {
+    arg.action(clap::ArgAction::Append).value_parser(Lint::NAMES)
+}
+
+fn include_paths(matches: &ArgMatches) -> Vec<PathBuf> {
+    matches
+        .get_many::<String>("include")
+        .map_or_else(Vec::new, |paths| paths.map(PathBuf::from).collect())
+}
+
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new(STDIO)
+}
+
+// The `--output` value if given; otherwise stdout for stdin input, or the
+// input file with `extension`.
+fn output_file(matches: &ArgMatches, input_file: &Path, extension: &str) -> PathBuf {
+    match matches.get_one::<String>("output") {
+        Some(output_file) => PathBuf::from(output_file),
+        None if is_stdio(input_file) => PathBuf::from(STDIO),
+        None => input_file.with_extension(extension),
+    }
+}
+
+// Path for an extra output such as a listing, placed next to whichever of the
+// output or input is a real file.
+fn sidecar_file(input_file: &Path, output_file: &Path, extension: &str) -> Result<PathBuf> {
+    [output_file, input_file]
+        .into_iter()
+        .find(|path| !is_stdio(path))
+        .map(|path| path.with_extension(extension))
+        .ok_or_else(|| format!("A .{} file needs a named input or output file", extension).into())
+}
 
-    Ok(Config {
-        input_file,
-        output_file,
-    })
+fn parse_range(range: &str) -> Result<Range<u16>> {
+    let (start, end) = range
+        .split_once("..")
+        .ok_or_else(|| format!("Invalid range '{}', expected START..END", range))?;
+    Ok(start.trim().parse()?..end.trim().parse()?)
 }
 
 
+// Assembles `source` entirely in memory: nothing is printed, and no files are
+// read unless `options.include_paths` gives `.include` directories to search.
+// Errors and warnings are returned to the caller to render or inspect.
+pub fn assemble_str(source: &str, options: Options) -> std::result::Result<Program, Diagnostics> {
+    assemble_program(source.as_bytes(), None, options)
+}
+
+// Formats `source` the way `hack_assembler fmt` does. Only whitespace changes,
+// so the result assembles to the same program.
+pub fn format_str(source: &str, indent: usize) -> String {
+    formatter::format(source, indent)
+}
+
+// Translates a .vm file, or every .vm file in a directory as one program.
+fn translate_vm(path: &Path) -> std::result::Result<String, AssemblyError> {
+    let files = if path.is_dir() {
+        let mut files: Vec<PathBuf> = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<_>>()?;
+        files.retain(|file| file.is_file() && file.extension().unwrap_or_default() == "vm");
+        if files.is_empty() {
+            return Err(AssemblyError::Other(format!("No .vm files found in {}", path.display()).into()));
+        }
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut translator = Translator::new();
+    for file in &files {
+        let source = std::fs::read_to_string(file)?;
+        let name = file.file_stem().unwrap_or_default().to_string_lossy();
+        translator.translate(&name, Some(file), &source);
+    }
+    translator.finish().map_err(AssemblyError::AsmErrors)
+}
+
+fn assemble_program<R: BufRead>(
+    reader: R,
+    file: Option<&Path>,
+    options: Options,
+) -> std::result::Result<Program, Diagnostics> {
+    let mut assembler =
+        assembler::Assembler::new(reader, std::io::sink(), SymbolTable::default()).with_options(options);
+    if let Some(file) = file {
+        assembler = assembler.with_file(file);
+    }
+    match assembler.assemble() {
+        Ok(()) => Ok(assembler.into_program()),
+        Err(AssemblyError::AsmErrors(errors)) => Err(Diagnostics {
+            errors,
+            warnings: assembler.warnings,
+        }),
+        Err(AssemblyError::Other(err)) => Err(Diagnostics {
+            errors: vec![AsmError::error(ErrorCode::Internal, 0, err.to_string())],
+            warnings: assembler.warnings,
+        }),
+    }
+}
+
+// Runs every config, carrying on past failures. With more than one input a
+// summary is printed and the overall result fails if any of them did.
+pub fn run_all(configs: Vec<Config>) -> std::result::Result<(), AssemblyError> {
+    if configs.len() == 1 {
+        return configs.into_iter().try_for_each(run);
+    }
+
+    let total = configs.len();
+    let mut failed = Vec::new();
+    for config in configs {
+        let input_file = config.input_file.clone();
+        if let Err(err) = run(config) {
+            eprintln!("Error processing {}: {}", input_file.display(), err);
+            failed.push(input_file);
+        }
+    }
+
+    println!("{} succeeded, {} failed", total - failed.len(), failed.len());
+    for input_file in &failed {
+        println!("  failed: {}", input_file.display());
+    }
+    if !failed.is_empty() {
+        return Err(AssemblyError::Other(
+            format!("{} of {} files failed", failed.len(), total).into(),
+        ));
+    }
+    Ok(())
+}
+
 pub fn run(config: Config) -> std::result::Result<(), AssemblyError> {
-    let reader = BufReader::new(File::open(&config.input_file)?);
-    let output_file_path = &config.output_file.clone();
-    let writer = BufWriter::new(File::create(output_file_path)?);
-    let symbol_table = SymbolTable::default();
+    match config.mode {
+        Mode::Assemble => {
+            let mut words_saved = 0;
+            write_output(&config, |reader, writer| {
+                let mut assembler = assembler::Assembler::new(reader, writer, SymbolTable::default())
+                    .with_options(config.options.clone())
+                    .with_format(config.format);
+                if !is_stdio(&config.input_file) {
+                    assembler = assembler.with_file(&config.input_file);
+                }
+                if config.listing_file.is_some() {
+                    assembler = assembler.with_listing();
+                }
+                let result = assembler.assemble();
+                for warning in &assembler.warnings {
+                    eprintln!("{}", warning);
+                }
+                result?;
+                words_saved = assembler.words_saved();
+                if let Some(listing_file) = &config.listing_file {
+                    assembler.write_listing(BufWriter::new(File::create(listing_file)?))?;
+                }
+                match config.symbols {
+                    Some(SymbolFormat::Sym) => {
+                        let sym_file = sidecar_file(&config.input_file, &config.output_file, "sym")?;
+                        assembler.symbol_table().write_sym(BufWriter::new(File::create(sym_file)?))?;
+                    }
+                    Some(SymbolFormat::Json) => println!("{}", assembler.symbol_table().to_json()),
+                    None => {}
+                }
+                Ok(())
+            })?;
+            // Keep stdout parseable when it carries the program or the JSON symbol table.
+            if !is_stdio(&config.output_file) && !matches!(config.symbols, Some(SymbolFormat::Json)) {
+                println!(
+                    "Successfully assembled the file: {}",
+                    config.output_file.display()
+                );
+                if config.options.optimize {
+                    println!("The optimizer saved {} word(s)", words_saved);
+                }
+            }
+        }
+        Mode::Disassemble => {
+            write_output(&config, |reader, writer| {
+                disassembler::Disassembler::new(reader, writer).disassemble()
+            })?;
+            if !is_stdio(&config.output_file) {
+                println!(
+                    "Successfully disassembled the file: {}",
+                    config.output_file.display()
+                );
+            }
+        }
+        Mode::Lint(ref levels) => {
+            let reader: Box<dyn BufRead> = if is_stdio(&config.input_file) {
+                Box::new(std::io::stdin().lock())
+            } else {
+                Box::new(BufReader::new(File::open(&config.input_file)?))
+            };
+            let mut assembler = assembler::Assembler::new(reader, std::io::sink(), SymbolTable::default())
+                .with_options(config.options.clone());
+            if !is_stdio(&config.input_file) {
+                assembler = assembler.with_file(&config.input_file);
+            }
+            let result = assembler.assemble();
+            for warning in assembler.unlinted_warnings() {
+                eprintln!("{}", warning);
+            }
+            result?;
+
+            let diagnostics = assembler.lint(levels);
+            for diagnostic in &diagnostics {
+                println!("{}", diagnostic);
+            }
+            let denied = diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .count();
+            match (denied, diagnostics.len() - denied) {
+                (0, 0) => println!("{}: no problems found", config.input_file.display()),
+                (errors, warnings) => println!(
+                    "{}: {} warning(s), {} error(s)",
+                    config.input_file.display(),
+                    warnings,
+                    errors
+                ),
+            }
+            if denied > 0 {
+                return Err(AssemblyError::Other(format!("{} denied lint(s) found", denied).into()));
+            }
+        }
+        Mode::Format { check, indent } => {
+            let source = if is_stdio(&config.input_file) {
+                std::io::read_to_string(std::io::stdin())?
+            } else {
+                std::fs::read_to_string(&config.input_file)?
+            };
+            let formatted = formatter::format(&source, indent);
+            if check {
+                if formatted != source {
+                    return Err(AssemblyError::Other(
+                        format!("{} isn't formatted", config.input_file.display()).into(),
+                    ));
+                }
+            } else if is_stdio(&config.output_file) {
+                print!("{}", formatted);
+            } else if formatted != source {
+                std::fs::write(&config.output_file, formatted)?;
+                println!("Formatted the file: {}", config.output_file.display());
+            }
+        }
+        Mode::Vm { asm } => {
+            let asm_code = translate_vm(&config.input_file)?;
+            let output: Vec<u8> = if asm {
+                asm_code.into_bytes()
+            } else {
+                let program = assemble_program(asm_code.as_bytes(), None, config.options.clone())
+                    .map_err(|diagnostics| AssemblyError::AsmErrors(diagnostics.errors))?;
+                let mut output = Vec::new();
+                config.format.write(&program.words, &mut output)?;
+                output
+            };
+            if is_stdio(&config.output_file) {
+                std::io::stdout().write_all(&output)?;
+            } else {
+                std::fs::write(&config.output_file, output)?;
+                println!("Successfully translated to the file: {}", config.output_file.display());
+            }
+        }
+        Mode::Test { max_steps } => {
+            let result = run_script(&config.input_file, config.options.clone(), max_steps)?;
+            for echo in &result.echoes {
+                println!("{}", echo);
+            }
+            if let Some(mismatch) = result.mismatch {
+                println!("expected: {}", mismatch.expected);
+                println!("  actual: {}", mismatch.actual);
+                return Err(AssemblyError::Other(
+                    format!("Comparison failure at line {}", mismatch.line).into(),
+                ));
+            }
+            match result.compare_file {
+                Some(_) => println!("{}: End of script - Comparison ended successfully", config.input_file.display()),
+                None => println!("{}: End of script", config.input_file.display()),
+            }
+        }
+        Mode::Debug { max_steps } => {
+            let mut debugger = Debugger::load(&config.input_file, config.options.clone())?.with_max_steps(max_steps);
+            println!("Debugging {}; type `help` for a list of commands", config.input_file.display());
+            debugger.repl(std::io::stdin().lock(), std::io::stdout())?;
+        }
+        Mode::Run { max_steps, ref dump } => {
+            let mut emulator = if config.input_file.extension().unwrap_or_default() == "asm" {
+                let reader = BufReader::new(File::open(&config.input_file)?);
+                let program = assemble_program(reader, Some(&config.input_file), config.options.clone())
+                    .map_err(|diagnostics| AssemblyError::AsmErrors(diagnostics.errors))?;
+                Emulator::new(program.words)
+            } else {
+                Emulator::from_hack(BufReader::new(File::open(&config.input_file)?))?
+            };
+            let halt = emulator.run(max_steps);
+            println!("Stopped after {} steps: {}", emulator.steps(), halt);
+            print!("{}", emulator.dump(dump.clone()));
+        }
+    }
+    Ok(())
+}
 
-    let mut assembler = assembler::Assembler::new(reader, writer, config, symbol_table);
+// Runs `f` against the input and output (files or stdio), removing a
+// partially written output file if it fails.
+fn write_output<F>(config: &Config, f: F) -> std::result::Result<(), AssemblyError>
+where
+    F: FnOnce(Box<dyn BufRead>, Box<dyn Write>) -> std::result::Result<(), AssemblyError>,
+{
+    let reader: Box<dyn BufRead> = if is_stdio(&config.input_file) {
+        Box::new(std::io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(&config.input_file)?))
+    };
+    let output_file_path = &config.output_file;
+    if is_stdio(output_file_path) {
+        return f(reader, Box::new(BufWriter::new(std::io::stdout())));
+    }
+    let writer = Box::new(BufWriter::new(File::create(output_file_path)?));
 
-    if let Err(e) = assembler.assemble() {
+    if let Err(e) = f(reader, writer) {
         std::fs::remove_file(output_file_path).map_err(|err| AssemblyError::Other(Box::new(err)))?;
         return Err(e);
     }
@@ -0,0 +1,480 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::assembler::ParsedLine;
+use crate::error::ErrorCode;
+use crate::instruction::{Instruction, Jump};
+use crate::preprocessor::SourceLine;
+use crate::suggest::closest;
+use crate::symbol_table::KBD;
+use crate::{AsmError, SymbolKind, SymbolTable};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    // A variable that appears only once is usually a misspelling of another.
+    SingleUseVariable,
+    UnusedLabel,
+    // Instructions after an unconditional jump that no label points at.
+    UnreachableCode,
+    // `@LABEL` followed by an instruction that reads or writes M.
+    MemoryAtJumpTarget,
+    // A jump whose target comes from a number, variable or constant.
+    JumpWithoutLabel,
+    // The program doesn't end in a jump, so the CPU runs off the end of it.
+    MissingHalt,
+    WriteToKbd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LintLevel {
+    Allow,
+    Warn,
+    Deny,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 7] = [
+        Lint::SingleUseVariable,
+        Lint::UnusedLabel,
+        Lint::UnreachableCode,
+        Lint::MemoryAtJumpTarget,
+        Lint::JumpWithoutLabel,
+        Lint::MissingHalt,
+        Lint::WriteToKbd,
+    ];
+
+    pub const NAMES: [&'static str; 7] = [
+        "single-use-variable",
+        "unused-label",
+        "unreachable-code",
+        "memory-at-jump-target",
+        "jump-without-label",
+        "missing-halt",
+        "write-to-kbd",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        Self::NAMES[*self as usize]
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES
+            .iter()
+            .position(|candidate| *candidate == name)
+            .map(|index| Self::ALL[index])
+    }
+
+    pub fn code(&self) -> &'static str {
+        ["L0001", "L0002", "L0003", "L0004", "L0005", "L0006", "L0007"][*self as usize]
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+// The level of each lint. Everything warns unless told otherwise.
+#[derive(Debug, Clone)]
+pub struct LintLevels {
+    levels: HashMap<Lint, LintLevel>,
+}
+
+impl LintLevels {
+    pub fn new() -> Self {
+        LintLevels {
+            levels: HashMap::new(),
+        }
+    }
+
+    pub fn set(&mut self, lint: Lint, level: LintLevel) {
+        self.levels.insert(lint, level);
+    }
+
+    pub fn level(&self, lint: Lint) -> LintLevel {
+        self.levels.get(&lint).copied().unwrap_or(LintLevel::Warn)
+    }
+}
+
+impl Default for LintLevels {
+    fn default() -> Self {
+        LintLevels::new()
+    }
+}
+
+// Parses a `// lint:allow(a, b)`, `lint:warn(...)` or `lint:deny(...)`
+// comment on `source`. Unknown lint names are returned as warnings.
+fn directive(source: &SourceLine, warnings: &mut Vec<AsmError>) -> Vec<(Lint, LintLevel)> {
+    let Some(comment_start) = source.text.find("//") else {
+        return Vec::new();
+    };
+    let comment = source.text[comment_start + 2..].trim();
+    let Some(rest) = comment.strip_prefix("lint:") else {
+        return Vec::new();
+    };
+    let levels = [("allow", LintLevel::Allow), ("warn", LintLevel::Warn), ("deny", LintLevel::Deny)];
+    let Some((level, names)) = levels.iter().find_map(|(keyword, level)| {
+        let names = rest.strip_prefix(keyword)?.trim().strip_prefix('(')?.strip_suffix(')')?;
+        Some((*level, names))
+    }) else {
+        warnings.push(
+            source
+                .error(ErrorCode::InvalidDirective, "malformed lint comment", comment)
+                .into_warning()
+                .with_hint("write it as `// lint:allow(NAME, ...)`, `lint:warn(...)` or `lint:deny(...)`"),
+        );
+        return Vec::new();
+    };
+
+    let mut directives = Vec::new();
+    for name in names.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match Lint::from_name(name) {
+            Some(lint) => directives.push((lint, level)),
+            None => {
+                let mut warning = source
+                    .error(ErrorCode::InvalidDirective, format!("unknown lint `{}`", name), name)
+                    .into_warning();
+                if let Some(suggestion) = closest(name, Lint::NAMES) {
+                    warning = warning.with_hint(format!("did you mean `{}`?", suggestion));
+                }
+                warnings.push(warning);
+            }
+        }
+    }
+    directives
+}
+
+struct Linter<'a> {
+    symbol_table: &'a SymbolTable,
+    // Lints denied on the command line, which source comments can't relax.
+    command_line: &'a LintLevels,
+    levels: LintLevels,
+    // Levels set by a trailing comment, by index into the parsed lines.
+    line_levels: HashMap<usize, Vec<(Lint, LintLevel)>>,
+    reported: HashSet<Lint>,
+    diagnostics: Vec<AsmError>,
+}
+
+impl Linter<'_> {
+    fn report(
+        &mut self,
+        lint: Lint,
+        index: usize,
+        parsed: &ParsedLine,
+        message: String,
+        needle: &str,
+    ) -> Option<&mut AsmError> {
+        let level = self
+            .line_levels
+            .get(&index)
+            .and_then(|levels| levels.iter().rev().find(|(line_lint, _)| *line_lint == lint))
+            .map_or_else(|| self.levels.level(lint), |(_, level)| *level);
+        let denied_on_command_line = self.command_line.level(lint) == LintLevel::Deny;
+        let level = match denied_on_command_line {
+            true => LintLevel::Deny,
+            false => level,
+        };
+        let mut diagnostic = match level {
+            LintLevel::Allow => return None,
+            LintLevel::Warn => parsed.source.error(ErrorCode::Lint(lint), message, needle).into_warning(),
+            LintLevel::Deny => parsed.source.error(ErrorCode::Lint(lint), message, needle),
+        };
+        // Like rustc, explain where the level came from the first time only.
+        if self.reported.insert(lint) {
+            let origin = match level {
+                LintLevel::Deny => format!("`{}` is denied", lint),
+                _ => format!("`{}` is on by default", lint),
+            };
+            diagnostic = diagnostic.with_note(match denied_on_command_line {
+                true => format!("{} with `-D {}`, which `// lint:allow` can't override", origin, lint),
+                false => format!("{}; allow it with `-A {}` or `// lint:allow({})`", origin, lint, lint),
+            });
+        }
+        self.diagnostics.push(diagnostic);
+        self.diagnostics.last_mut()
+    }
+
+    fn is_label(&self, name: &str) -> bool {
+        self.symbol_table.get(name).is_some_and(|symbol| symbol.kind == SymbolKind::Label)
+    }
+}
+
+// Runs every lint over the parsed program. `comments` are the comment-only
+// lines, which may hold `// lint:` directives for the whole program.
+pub fn check(
+    parsed_lines: &[ParsedLine],
+    comments: &[SourceLine],
+    symbol_table: &SymbolTable,
+    levels: &LintLevels,
+) -> Vec<AsmError> {
+    let command_line = levels;
+    let mut directive_warnings = Vec::new();
+    let mut levels = levels.clone();
+    for comment in comments {
+        for (lint, level) in directive(comment, &mut directive_warnings) {
+            levels.set(lint, level);
+        }
+    }
+    let line_levels = parsed_lines
+        .iter()
+        .enumerate()
+        .map(|(index, parsed)| (index, directive(&parsed.source, &mut directive_warnings)))
+        .filter(|(_, directives)| !directives.is_empty())
+        .collect();
+
+    let mut linter = Linter {
+        symbol_table,
+        command_line,
+        levels,
+        line_levels,
+        reported: HashSet::new(),
+        diagnostics: directive_warnings,
+    };
+    check_symbols(&mut linter, parsed_lines);
+    check_flow(&mut linter, parsed_lines);
+    linter.diagnostics
+}
+
+fn check_symbols(linter: &mut Linter, parsed_lines: &[ParsedLine]) {
+    let mut uses: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (index, parsed) in parsed_lines.iter().enumerate() {
+        match &parsed.instruction {
+            Instruction::Variable(name) => uses.entry(name).or_default().push(index),
+            Instruction::Expression(expr) => {
+                for name in expr.symbols() {
+                    uses.entry(name).or_default().push(index);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut single_uses: Vec<(&str, usize)> = uses
+        .iter()
+        .filter(|(name, indices)| {
+            indices.len() == 1 && linter.symbol_table.get(name).is_some_and(|symbol| symbol.kind == SymbolKind::Variable)
+        })
+        .map(|(name, indices)| (*name, indices[0]))
+        .collect();
+    single_uses.sort_by_key(|(_, index)| *index);
+    for (name, index) in single_uses {
+        let others = uses.keys().copied().filter(|other| *other != name);
+        let suggestion = closest(name, others).map(|other| format!("did you mean `{}`?", other));
+        let message = format!("variable `{}` is only used once", name);
+        if let Some(diagnostic) = linter.report(Lint::SingleUseVariable, index, &parsed_lines[index], message, name) {
+            if let Some(suggestion) = suggestion {
+                diagnostic.hint = Some(suggestion);
+            }
+        }
+    }
+
+    for (index, parsed) in parsed_lines.iter().enumerate() {
+        if let Instruction::L(label) = &parsed.instruction {
+            if !uses.contains_key(label.as_str()) && linter.is_label(label) {
+                let message = format!("label `{}` is never used", label);
+                linter.report(Lint::UnusedLabel, index, parsed, message, label);
+            }
+        }
+    }
+}
+
+fn check_flow(linter: &mut Linter, parsed_lines: &[ParsedLine]) {
+    // Indices of the instructions that occupy ROM, and the ROM addresses that
+    // a label points at.
+    let rom: Vec<usize> = (0..parsed_lines.len())
+        .filter(|index| parsed_lines[*index].instruction.occupies_rom())
+        .collect();
+    let labelled: HashSet<u16> = linter
+        .symbol_table
+        .iter()
+        .filter(|(_, symbol)| symbol.kind == SymbolKind::Label)
+        .map(|(_, symbol)| symbol.address)
+        .collect();
+
+    for (address, &index) in rom.iter().enumerate() {
+        let Instruction::C(c_instruction) = &parsed_lines[index].instruction else {
+            continue;
+        };
+        let previous = address.checked_sub(1).map(|previous| (rom[previous], &parsed_lines[rom[previous]]));
+        let loaded = previous.and_then(|(_, parsed)| loaded_symbol(&parsed.instruction));
+
+        if c_instruction.uses_memory() {
+            if let (Some((previous_index, previous)), Some(name)) = (previous, loaded) {
+                if linter.is_label(name) {
+                    let message = format!("label `{}` is used as a RAM address", name);
+                    if let Some(diagnostic) =
+                        linter.report(Lint::MemoryAtJumpTarget, previous_index, previous, message, name)
+                    {
+                        diagnostic.notes.insert(0, format!("`{}` on the next line accesses RAM[A]", c_instruction));
+                    }
+                }
+            }
+        }
+
+        if c_instruction.jump() != Jump::Null {
+            if let Some((previous_index, previous)) = previous {
+                let target_is_label = match &previous.instruction {
+                    Instruction::Variable(name) => linter.is_label(name),
+                    Instruction::Expression(expr) => expr.symbols().iter().any(|name| linter.is_label(name)),
+                    Instruction::A(_) => false,
+                    // A computed in a C-instruction, e.g. `A=M` for a return
+                    // address, is deliberate.
+                    _ => true,
+                };
+                if !target_is_label {
+                    let target = previous.source.code();
+                    let message = format!("jump target `{}` isn't a label", target);
+                    linter.report(Lint::JumpWithoutLabel, previous_index, previous, message, target);
+                }
+            }
+        }
+
+        if c_instruction.writes_memory() {
+            if let Some((_, previous)) = previous {
+                let is_kbd = match &previous.instruction {
+                    Instruction::A(a_instruction) => a_instruction.value() == KBD,
+                    _ => loaded.is_some_and(|name| linter.symbol_table.get_address(name) == Some(&KBD)),
+                };
+                if is_kbd {
+                    let message = "write to the keyboard register `KBD`, which is read-only".to_string();
+                    let needle = c_instruction.to_string();
+                    if let Some(diagnostic) = linter.report(Lint::WriteToKbd, index, &parsed_lines[index], message, &needle) {
+                        diagnostic.notes.insert(0, format!("`{}` sets A to {}", previous.source.code(), KBD));
+                    }
+                }
+            }
+        }
+
+        if c_instruction.jump() == Jump::JMP {
+            if let Some(&next) = rom.get(address + 1) {
+                if !labelled.contains(&((address + 1) as u16)) {
+                    linter.report(
+                        Lint::UnreachableCode,
+                        next,
+                        &parsed_lines[next],
+                        "unreachable instruction after an unconditional jump".to_string(),
+                        parsed_lines[next].source.code(),
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(&last) = rom.last() {
+        let halts = matches!(&parsed_lines[last].instruction, Instruction::C(c) if c.jump() == Jump::JMP);
+        if !halts {
+            let parsed = &parsed_lines[last];
+            if let Some(diagnostic) = linter.report(
+                Lint::MissingHalt,
+                last,
+                parsed,
+                "the program doesn't end in an infinite loop".to_string(),
+                parsed.source.code(),
+            ) {
+                diagnostic.hint = Some("end it with `(END)`, `@END`, `0;JMP` so the CPU doesn't run past it".to_string());
+            }
+        }
+    }
+}
+
+// The symbol an A-instruction loads, if it loads a single symbol.
+fn loaded_symbol(instruction: &Instruction) -> Option<&str> {
+    match instruction {
+        Instruction::Variable(name) => Some(name),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::Severity;
+    use std::io::Cursor;
+
+    fn lint(source: &str, levels: &LintLevels) -> Vec<AsmError> {
+        let mut assembler = Assembler::new(Cursor::new(source), Vec::new(), SymbolTable::default());
+        assembler.assemble().unwrap();
+        assembler.lint(levels)
+    }
+
+    fn codes(diagnostics: &[AsmError]) -> Vec<(ErrorCode, usize)> {
+        diagnostics.iter().map(|diagnostic| (diagnostic.code, diagnostic.line)).collect()
+    }
+
+    #[test]
+    fn test_lints() {
+        let source = "\
+@counter
+M=0
+(LOOP)
+@LOOP
+D=M;JGT
+@R1
+0;JMP
+@KBD
+M=1
+(UNUSED)
+@5
+D;JEQ
+";
+        assert_eq!(
+            codes(&lint(source, &LintLevels::new())),
+            [
+                (ErrorCode::Lint(Lint::SingleUseVariable), 1),
+                (ErrorCode::Lint(Lint::UnusedLabel), 10),
+                (ErrorCode::Lint(Lint::MemoryAtJumpTarget), 4),
+                (ErrorCode::Lint(Lint::JumpWithoutLabel), 6),
+                (ErrorCode::Lint(Lint::UnreachableCode), 8),
+                (ErrorCode::Lint(Lint::WriteToKbd), 9),
+                (ErrorCode::Lint(Lint::JumpWithoutLabel), 11),
+                (ErrorCode::Lint(Lint::MissingHalt), 12),
+            ]
+        );
+
+        let clean = "@i\nM=0\n(LOOP)\n@i\nM=M+1\n@LOOP\n0;JMP\n";
+        assert!(lint(clean, &LintLevels::new()).is_empty());
+    }
+
+    #[test]
+    fn test_levels() {
+        let source = "\
+// lint:allow(missing-halt)
+@x // lint:deny(single-use-variable)
+M=0
+@y
+M=0
+";
+        let diagnostics = lint(source, &LintLevels::new());
+        assert_eq!(
+            codes(&diagnostics),
+            [
+                (ErrorCode::Lint(Lint::SingleUseVariable), 2),
+                (ErrorCode::Lint(Lint::SingleUseVariable), 4),
+            ]
+        );
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+
+        // A deny on the command line wins over an allow in the source.
+        let mut levels = LintLevels::new();
+        levels.set(Lint::SingleUseVariable, LintLevel::Allow);
+        levels.set(Lint::MissingHalt, LintLevel::Deny);
+        let diagnostics = lint(source, &levels);
+        assert_eq!(
+            codes(&diagnostics),
+            [(ErrorCode::Lint(Lint::SingleUseVariable), 2), (ErrorCode::Lint(Lint::MissingHalt), 5)]
+        );
+        assert_eq!(diagnostics[1].severity, Severity::Error);
+    }
+
+    #[test]
+    fn test_directive_errors() {
+        let source = "// lint:allow(unused-lable)\n// lint:forbid(x)\n(END)\n@END\n0;JMP\n";
+        let diagnostics = lint(source, &LintLevels::new());
+        assert_eq!(codes(&diagnostics), [(ErrorCode::InvalidDirective, 1), (ErrorCode::InvalidDirective, 2)]);
+        assert_eq!(diagnostics[0].hint.as_deref(), Some("did you mean `unused-label`?"));
+        assert_eq!(diagnostics[1].message, "malformed lint comment");
+    }
+}
@@ -0,0 +1,801 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Cursor, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::assembler::{sanitize_line, Assembler};
+use crate::error::{AssemblyError, ErrorCode};
+use crate::formatter::is_macro_invocation;
+use crate::instruction::{Comp, Instruction, Jump};
+use crate::json::Json;
+use crate::lexer::{is_symbol, is_symbol_char, tokenize, TokenKind};
+use crate::preprocessor::SourceLine;
+use crate::{AsmError, LintLevels, Severity, SymbolKind, SymbolTable};
+
+// A Language Server Protocol server for Hack assembly. Messages are JSON-RPC
+// with `Content-Length` headers, read from `input` and written to `output`,
+// so editors can run it over stdio. Documents are synced in full and
+// reassembled on every change.
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const REQUEST_FAILED: i64 = -32803;
+
+// LSP's CompletionItemKind values.
+const FUNCTION: usize = 3;
+const VARIABLE: usize = 6;
+const KEYWORD: usize = 14;
+const CONSTANT: usize = 21;
+
+// A symbol as written in the document. Columns are byte offsets, which are
+// the UTF-16 offsets LSP counts in since Hack source is ASCII.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Occurrence {
+    name: String,
+    line: usize,
+    span: Range<usize>,
+    // `(NAME)` or `.equ NAME`, as opposed to a use.
+    definition: bool,
+}
+
+struct Analysis {
+    diagnostics: Vec<AsmError>,
+    occurrences: Vec<Occurrence>,
+    symbol_table: SymbolTable,
+    // The ROM address and word of each instruction, by line. A macro
+    // invocation or `.include` has one for each instruction it expands to.
+    words: HashMap<usize, Vec<(usize, u16)>>,
+}
+
+impl Analysis {
+    fn occurrence_at(&self, line: usize, column: usize) -> Option<&Occurrence> {
+        self.occurrences
+            .iter()
+            .find(|occurrence| occurrence.line == line && occurrence.span.contains(&column))
+            .or_else(|| {
+                // The cursor is often just after the name.
+                self.occurrences
+                    .iter()
+                    .find(|occurrence| occurrence.line == line && occurrence.span.end == column)
+            })
+    }
+
+    fn occurrences_of<'a>(&'a self, name: &'a str) -> impl DoubleEndedIterator<Item = &'a Occurrence> {
+        self.occurrences.iter().filter(move |occurrence| occurrence.name == name)
+    }
+}
+
+struct Document {
+    text: String,
+    analysis: Analysis,
+}
+
+pub struct LanguageServer {
+    documents: HashMap<String, Document>,
+    shut_down: bool,
+    exited: bool,
+}
+
+impl LanguageServer {
+    pub fn new() -> Self {
+        LanguageServer {
+            documents: HashMap::new(),
+            shut_down: false,
+            exited: false,
+        }
+    }
+
+    // Handles messages until `exit` or the end of the input.
+    pub fn serve<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        while !self.exited {
+            let Some(body) = read_message(&mut input)? else {
+                break;
+            };
+            let replies = match Json::parse(&body) {
+                Ok(message) => self.handle(&message),
+                Err(e) => vec![error_response(Json::Null, PARSE_ERROR, format!("invalid JSON: {}", e))],
+            };
+            for reply in replies {
+                write_message(&mut output, &reply.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Whether `shutdown` was requested before `exit`, which is when the
+    // protocol expects the server to exit successfully.
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    // Returns the messages to send back: a response for a request, and
+    // diagnostics for notifications that change a document.
+    fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").as_str().unwrap_or_default();
+        let params = message.get("params");
+        let id = message.get("id");
+        if id.is_null() {
+            return self.notify(method, params);
+        }
+        let result = match method {
+            "initialize" => Ok(capabilities()),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Json::Null)
+            }
+            "textDocument/definition" => Ok(self.definition(params)),
+            "textDocument/references" => Ok(self.references(params)),
+            "textDocument/hover" => Ok(self.hover(params)),
+            "textDocument/completion" => Ok(self.completion(params)),
+            "textDocument/rename" => self.rename(params),
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method))),
+        };
+        let response = match result {
+            Ok(result) => Json::object([("jsonrpc", "2.0".into()), ("id", id.clone()), ("result", result)]),
+            Err((code, message)) => error_response(id.clone(), code, message),
+        };
+        vec![response]
+    }
+
+    fn notify(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or_default();
+        let text = match method {
+            "textDocument/didOpen" => params.get("textDocument").get("text").as_str(),
+            // Only full syncs are advertised, so the last change is the whole text.
+            "textDocument/didChange" => params
+                .get("contentChanges")
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change.get("text").as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return vec![publish_diagnostics(uri, Vec::new())];
+            }
+            "exit" => {
+                self.exited = true;
+                return Vec::new();
+            }
+            _ => None,
+        };
+        let Some(text) = text else {
+            return Vec::new();
+        };
+        let path = uri_to_path(uri);
+        let document = Document {
+            text: text.to_string(),
+            analysis: analyze(text, path.as_deref()),
+        };
+        let lines: Vec<&str> = document.text.lines().collect();
+        let diagnostics = document
+            .analysis
+            .diagnostics
+            .iter()
+            .map(|error| diagnostic(error, &lines))
+            .collect();
+        self.documents.insert(uri.to_string(), document);
+        vec![publish_diagnostics(uri, diagnostics)]
+    }
+
+    // The document a request is about, with its URI and the position's line
+    // and column.
+    fn position<'a>(&'a self, params: &'a Json) -> Option<(&'a str, &'a Document, usize, usize)> {
+        let uri = params.get("textDocument").get("uri").as_str()?;
+        let document = self.documents.get(uri)?;
+        let position = params.get("position");
+        Some((uri, document, position.get("line").as_usize()?, position.get("character").as_usize()?))
+    }
+
+    // Where a label or constant is defined. Variables have no definition, so
+    // their first use stands in for one.
+    fn definition(&self, params: &Json) -> Json {
+        let Some((uri, document, line, column)) = self.position(params) else {
+            return Json::Null;
+        };
+        let Some(occurrence) = document.analysis.occurrence_at(line, column) else {
+            return Json::Null;
+        };
+        let analysis = &document.analysis;
+        let definition = analysis
+            .occurrences_of(&occurrence.name)
+            .find(|occurrence| occurrence.definition)
+            .or_else(|| analysis.occurrences_of(&occurrence.name).next());
+        definition.map_or(Json::Null, |occurrence| location(uri, occurrence))
+    }
+
+    fn references(&self, params: &Json) -> Json {
+        let Some((uri, document, line, column)) = self.position(params) else {
+            return Json::Null;
+        };
+        let Some(occurrence) = document.analysis.occurrence_at(line, column) else {
+            return Json::Null;
+        };
+        let include_declaration = params.get("context").get("includeDeclaration").as_bool().unwrap_or(true);
+        let locations = document
+            .analysis
+            .occurrences_of(&occurrence.name)
+            .filter(|occurrence| include_declaration || !occurrence.definition)
+            .map(|occurrence| location(uri, occurrence))
+            .collect::<Vec<Json>>();
+        locations.into()
+    }
+
+    // What a symbol resolves to, and the address and encoding of the
+    // instructions on the line.
+    fn hover(&self, params: &Json) -> Json {
+        let Some((_, document, line, column)) = self.position(params) else {
+            return Json::Null;
+        };
+        let analysis = &document.analysis;
+        let mut sections = Vec::new();
+        let occurrence = analysis.occurrence_at(line, column);
+        if let Some(occurrence) = occurrence {
+            let name = &occurrence.name;
+            sections.push(match analysis.symbol_table.get(name) {
+                Some(symbol) => match symbol.kind {
+                    SymbolKind::Label => format!("`{}`: label at ROM[{}]", name, symbol.address),
+                    SymbolKind::Constant => format!("`{}`: constant equal to {}", name, symbol.address),
+                    SymbolKind::Variable => format!("`{}`: variable at RAM[{}]", name, symbol.address),
+                    SymbolKind::Predefined => format!("`{}`: predefined symbol for RAM[{}]", name, symbol.address),
+                },
+                None => format!("`{}`: unknown symbol", name),
+            });
+        }
+        for (address, word) in analysis.words.get(&line).into_iter().flatten() {
+            sections.push(format!("ROM[{}]: `{:016b}`", address, word));
+        }
+        if sections.is_empty() {
+            return Json::Null;
+        }
+        let contents = Json::object([("kind", "markdown".into()), ("value", sections.join("\n\n").into())]);
+        match occurrence {
+            Some(occurrence) => {
+                Json::object([("contents", contents), ("range", range(occurrence.line, &occurrence.span))])
+            }
+            None => Json::object([("contents", contents)]),
+        }
+    }
+
+    // Symbols after `@`, jumps after `;` and computations anywhere else an
+    // instruction can go.
+    fn completion(&self, params: &Json) -> Json {
+        let Some((_, document, line, column)) = self.position(params) else {
+            return Json::Array(Vec::new());
+        };
+        let text = document.text.lines().nth(line).unwrap_or_default();
+        let before = text.get(..column.min(text.len())).unwrap_or_default().trim_start();
+        let item = |label: &str, kind: usize, detail: String| {
+            Json::object([("label", label.into()), ("kind", kind.into()), ("detail", detail.into())])
+        };
+        let items: Vec<Json> = if before.contains("//") || before.starts_with(['(', '.']) {
+            Vec::new()
+        } else if before.starts_with('@') {
+            document
+                .analysis
+                .symbol_table
+                .iter()
+                .map(|(name, symbol)| {
+                    let (kind, detail) = match symbol.kind {
+                        SymbolKind::Label => (FUNCTION, format!("label at ROM[{}]", symbol.address)),
+                        SymbolKind::Constant => (CONSTANT, format!("constant equal to {}", symbol.address)),
+                        SymbolKind::Variable => (VARIABLE, format!("variable at RAM[{}]", symbol.address)),
+                        SymbolKind::Predefined => (CONSTANT, format!("predefined symbol for RAM[{}]", symbol.address)),
+                    };
+                    item(name, kind, detail)
+                })
+                .collect()
+        } else if before.contains(';') {
+            Jump::ALL
+                .iter()
+                .filter(|jump| **jump != Jump::Null)
+                .map(|jump| item(jump.mnemonic(), KEYWORD, "jump".to_string()))
+                .collect()
+        } else {
+            Comp::ALL
+                .iter()
+                .map(|comp| item(comp.mnemonic(), KEYWORD, "computation".to_string()))
+                .collect()
+        };
+        items.into()
+    }
+
+    fn rename(&self, params: &Json) -> Result<Json, (i64, String)> {
+        let Some((uri, document, line, column)) = self.position(params) else {
+            return Err((INVALID_PARAMS, "no open document at that position".to_string()));
+        };
+        let new_name = params.get("newName").as_str().unwrap_or_default();
+        let analysis = &document.analysis;
+        let occurrence = analysis
+            .occurrence_at(line, column)
+            .ok_or((REQUEST_FAILED, "there's no symbol here".to_string()))?;
+        // Labels defined in included files can't be renamed everywhere they're used.
+        let is_label = analysis
+            .symbol_table
+            .get(&occurrence.name)
+            .is_some_and(|symbol| symbol.kind == SymbolKind::Label)
+            && analysis.occurrences_of(&occurrence.name).any(|occurrence| occurrence.definition);
+        if !is_label {
+            return Err((REQUEST_FAILED, format!("only labels can be renamed, and `{}` isn't one", occurrence.name)));
+        }
+        if !is_symbol(new_name) {
+            return Err((REQUEST_FAILED, format!("`{}` isn't a valid symbol name", new_name)));
+        }
+        if analysis.symbol_table.contains(new_name) || analysis.occurrences_of(new_name).next().is_some() {
+            return Err((REQUEST_FAILED, format!("`{}` is already used", new_name)));
+        }
+        // A use the analysis can't see, such as one in an included file, would
+        // now name something else; the program changing gives it away.
+        let renamed_text = rename_in(&document.text, analysis, &occurrence.name, new_name);
+        let renamed = analyze(&renamed_text, uri_to_path(uri).as_deref());
+        if renamed.words != analysis.words {
+            return Err((
+                REQUEST_FAILED,
+                format!("`{}` is used where it can't be renamed, such as in an included file", occurrence.name),
+            ));
+        }
+        let edits: Vec<Json> = analysis
+            .occurrences_of(&occurrence.name)
+            .map(|occurrence| {
+                Json::object([("range", range(occurrence.line, &occurrence.span)), ("newText", new_name.into())])
+            })
+            .collect();
+        Ok(Json::object([("changes", Json::Object(vec![(uri.to_string(), edits.into())]))]))
+    }
+}
+
+impl Default for LanguageServer {
+    fn default() -> Self {
+        LanguageServer::new()
+    }
+}
+
+fn capabilities() -> Json {
+    let trigger_characters: Vec<Json> = ["@", "=", ";"].into_iter().map(Json::from).collect();
+    Json::object([
+        (
+            "capabilities",
+            Json::object([
+                // Full document sync.
+                ("textDocumentSync", 1.into()),
+                ("definitionProvider", true.into()),
+                ("referencesProvider", true.into()),
+                ("hoverProvider", true.into()),
+                ("completionProvider", Json::object([("triggerCharacters", trigger_characters.into())])),
+                ("renameProvider", true.into()),
+            ]),
+        ),
+        (
+            "serverInfo",
+            Json::object([("name", "hack_lsp".into()), ("version", env!("CARGO_PKG_VERSION").into())]),
+        ),
+    ])
+}
+
+// Assembles the document for diagnostics and addresses, and finds every
+// symbol in the lines that parse and in macro arguments. If assembly fails,
+// addresses are worked out from those lines instead, so navigation keeps
+// working while the code is broken.
+fn analyze(text: &str, path: Option<&Path>) -> Analysis {
+    let mut occurrences = Vec::new();
+    let mut instructions = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let Ok(Some(code)) = sanitize_line(line, index + 1) else {
+            continue;
+        };
+        let Ok(instruction) = Instruction::parse(&code, index + 1) else {
+            // A broken instruction still takes up a word, unlike a broken
+            // label or directive.
+            if !code.starts_with(['(', '.']) {
+                instructions.push((index, None));
+            }
+            // Symbols passed to a macro are used wherever it puts them.
+            if is_macro_invocation(&code) {
+                occurrences.extend(macro_arguments(line).into_iter().map(|(name, span)| Occurrence {
+                    name: name.to_string(),
+                    line: index,
+                    span,
+                    definition: false,
+                }));
+            }
+            continue;
+        };
+        let (mut names, definition) = match &instruction {
+            Instruction::L(name) | Instruction::Constant(name, _) => (vec![name.as_str()], true),
+            Instruction::Variable(name) => (vec![name.as_str()], false),
+            Instruction::Expression(expr) => (expr.symbols(), false),
+            Instruction::A(_) | Instruction::C(_) => (Vec::new(), false),
+        };
+        names.sort_unstable();
+        names.dedup();
+        let code = line.split("//").next().unwrap_or_default();
+        for name in names {
+            occurrences.extend(find_symbol(code, name).map(|span| Occurrence {
+                name: name.to_string(),
+                line: index,
+                span,
+                definition,
+            }));
+        }
+        instructions.push((index, Some(instruction)));
+    }
+
+    let mut assembler = Assembler::new(Cursor::new(text), io::sink(), SymbolTable::default());
+    if let Some(path) = path {
+        assembler = assembler.with_file(path);
+    }
+    let result = assembler.assemble();
+    let mut diagnostics: Vec<AsmError> = assembler.unlinted_warnings().cloned().collect();
+    match result {
+        Ok(()) => {
+            diagnostics.extend(assembler.lint(&LintLevels::new()));
+            let sources = assembler.source_lines();
+            let program = assembler.into_program();
+            let mut words: HashMap<usize, Vec<(usize, u16)>> = HashMap::new();
+            for (address, source) in sources.iter().enumerate() {
+                if let Some(line) = document_line(source) {
+                    words.entry(line).or_default().push((address, program.words[address]));
+                }
+            }
+            return Analysis {
+                diagnostics,
+                occurrences,
+                symbol_table: program.symbol_table,
+                words,
+            };
+        }
+        Err(AssemblyError::AsmErrors(errors)) => diagnostics.extend(errors),
+        Err(AssemblyError::Other(e)) => diagnostics.push(AsmError::error(ErrorCode::Internal, 1, e.to_string())),
+    }
+
+    let mut symbol_table = SymbolTable::default();
+    let mut address = 0;
+    for (_, instruction) in &instructions {
+        match instruction {
+            Some(Instruction::L(name)) if !symbol_table.contains(name) => symbol_table.add_label(name.clone(), address),
+            Some(Instruction::Constant(name, value)) if !symbol_table.contains(name) => {
+                symbol_table.add_constant(name.clone(), *value)
+            }
+            Some(instruction) if !instruction.occupies_rom() => {}
+            _ => address += 1,
+        }
+    }
+    let mut words = HashMap::new();
+    let mut address = 0;
+    for (line, instruction) in &instructions {
+        match instruction {
+            Some(instruction) if !instruction.occupies_rom() => continue,
+            Some(instruction) => {
+                if let Ok(Some(word)) = instruction.encode(&mut symbol_table, line + 1) {
+                    words.insert(*line, vec![(address, word)]);
+                }
+            }
+            None => {}
+        }
+        address += 1;
+    }
+    Analysis {
+        diagnostics,
+        occurrences,
+        symbol_table,
+        words,
+    }
+}
+
+// The symbols in the arguments of a macro invocation, with their spans in
+// `line`. A `\parameter` passed on by a macro body isn't a symbol yet.
+fn macro_arguments(line: &str) -> Vec<(&str, Range<usize>)> {
+    let tokens = tokenize(line.split("//").next().unwrap_or_default());
+    tokens
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(index, token)| token.kind == TokenKind::Symbol && tokens[index - 1].kind != TokenKind::Punct('\\'))
+        .map(|(_, token)| (token.text, token.span.clone()))
+        .collect()
+}
+
+// `text` with each occurrence of `name` the analysis found replaced by `new_name`.
+fn rename_in(text: &str, analysis: &Analysis, name: &str, new_name: &str) -> String {
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    // Backwards, so earlier spans on a line stay put.
+    for occurrence in analysis.occurrences_of(name).rev() {
+        lines[occurrence.line].replace_range(occurrence.span.clone(), new_name);
+    }
+    lines.join("\n")
+}
+
+// Each place `name` appears in `code` as a whole symbol.
+fn find_symbol<'a>(code: &'a str, name: &'a str) -> impl Iterator<Item = Range<usize>> + 'a {
+    code.match_indices(name)
+        .map(|(start, _)| start..start + name.len())
+        .filter(|span| !code[..span.start].ends_with(is_symbol_char) && !code[span.end..].starts_with(is_symbol_char))
+}
+
+// The line of the open document an instruction came from: its own, or the
+// macro invocation or `.include` that produced it.
+fn document_line(source: &SourceLine) -> Option<usize> {
+    let file = source.expansions.last().map_or(&source.file, |expansion| &expansion.file);
+    file.is_none().then(|| source.origin_line() - 1)
+}
+
+fn diagnostic(error: &AsmError, lines: &[&str]) -> Json {
+    let mut message = error.message.clone();
+    // Errors in included files can't be shown where they are, so they go on
+    // the first line with their location.
+    let (line, span) = match &error.file {
+        Some(file) => {
+            message = format!("{}:{}: {}", file.display(), error.line, message);
+            (0, 0..0)
+        }
+        None => (error.line.saturating_sub(1), error.span.clone()),
+    };
+    let span = match span.is_empty() {
+        // Without a span, the whole line is marked.
+        true => {
+            let text = lines.get(line).copied().unwrap_or_default();
+            let start = text.len() - text.trim_start().len();
+            start..text.trim_end().len().max(start)
+        }
+        false => span,
+    };
+    if let Some(hint) = &error.hint {
+        message.push_str(&format!("\nhelp: {}", hint));
+    }
+    for note in &error.notes {
+        message.push_str(&format!("\nnote: {}", note));
+    }
+    let severity = match error.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+    };
+    Json::object([
+        ("range", range(line, &span)),
+        ("severity", severity.into()),
+        ("code", error.code.as_str().into()),
+        ("source", "hack".into()),
+        ("message", message.into()),
+    ])
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Json>) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("method", "textDocument/publishDiagnostics".into()),
+        (
+            "params",
+            Json::object([("uri", uri.into()), ("diagnostics", diagnostics.into())]),
+        ),
+    ])
+}
+
+fn error_response(id: Json, code: i64, message: String) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("id", id),
+        (
+            "error",
+            Json::object([("code", Json::Number(code as f64)), ("message", message.into())]),
+        ),
+    ])
+}
+
+fn range(line: usize, span: &Range<usize>) -> Json {
+    let position = |character: usize| Json::object([("line", line.into()), ("character", character.into())]);
+    Json::object([("start", position(span.start)), ("end", position(span.end))])
+}
+
+fn location(uri: &str, occurrence: &Occurrence) -> Json {
+    Json::object([("uri", uri.into()), ("range", range(occurrence.line, &occurrence.span))])
+}
+
+// `file:///home/me/Prog.asm` to a path, so includes can be found. Other
+// schemes, such as unsaved documents, have no path.
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut index = 0;
+    while index < encoded.len() {
+        let escaped = encoded
+            .get(index + 1..index + 3)
+            .filter(|_| encoded[index] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                index += 3;
+            }
+            None => {
+                bytes.push(encoded[index]);
+                index += 1;
+            }
+        }
+    }
+    let path = String::from_utf8(bytes).ok()?;
+    // `file:///C:/Prog.asm` on Windows.
+    let path = match path.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => path[1..].to_string(),
+        _ => path,
+    };
+    Some(PathBuf::from(path))
+}
+
+// Reads one message body, skipping headers other than `Content-Length`.
+// Returns `None` at the end of the input, and an error for headers without a
+// usable `Content-Length`, since the body can't be found without it.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut length = None;
+    let mut has_headers = false;
+    let length = loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            match (length, has_headers) {
+                (Some(length), _) => break length,
+                (None, true) => return Err(invalid("message has no `Content-Length` header".to_string())),
+                (None, false) => continue,
+            }
+        }
+        has_headers = true;
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                let value = value.trim();
+                let parsed = value.parse::<usize>();
+                length = Some(parsed.map_err(|_| invalid(format!("invalid `Content-Length` header `{}`", value)))?);
+            }
+        }
+    };
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message<W: Write>(output: &mut W, body: &str) -> io::Result<()> {
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SOURCE: &str = "\
+// Counts down from 3
+    @3
+    D=A
+    @count
+    M=D
+(LOOP)
+    @count
+    MD=M-1
+    @LOOP
+    D;JGT
+(END)
+    @END
+    0;JMP
+";
+
+    fn open(uri: &str, text: &str) -> LanguageServer {
+        let mut server = LanguageServer::new();
+        let open = format!(
+            r#"{{"method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"{}","text":{}}}}}}}"#,
+            uri,
+            crate::json::json_string(text)
+        );
+        server.handle(&Json::parse(&open).unwrap());
+        server
+    }
+
+    fn request(server: &mut LanguageServer, method: &str, line: usize, character: usize, extra: &str) -> Json {
+        let uri = server.documents.keys().next().unwrap().clone();
+        let message = format!(
+            concat!(
+                r#"{{"id":1,"method":"{}","params":{{"textDocument":{{"uri":"{}"}},"#,
+                r#""position":{{"line":{},"character":{}}}{}}}}}"#
+            ),
+            method, uri, line, character, extra
+        );
+        let replies = server.handle(&Json::parse(&message).unwrap());
+        replies[0].clone()
+    }
+
+    #[test]
+    fn test_analysis() {
+        let analysis = analyze(SOURCE, None);
+        assert!(analysis.diagnostics.is_empty());
+        let loops: Vec<(usize, Range<usize>, bool)> = analysis
+            .occurrences_of("LOOP")
+            .map(|occurrence| (occurrence.line, occurrence.span.clone(), occurrence.definition))
+            .collect();
+        assert_eq!(loops, [(5, 1..5, true), (8, 5..9, false)]);
+        assert_eq!(analysis.words[&7], [(5, 0b1111110010011000)]);
+
+        // While the program doesn't assemble, addresses come from the lines that parse.
+        let analysis = analyze(&SOURCE.replace("D;JGT", "D;JGG"), None);
+        assert_eq!(analysis.diagnostics.len(), 1);
+        assert_eq!(analysis.symbol_table.get("END").unwrap().address, 8);
+        assert_eq!(analysis.words[&6], [(4, 16)]);
+    }
+
+    #[test]
+    fn test_requests() {
+        let mut server = open("untitled:1", SOURCE);
+        let definition = request(&mut server, "textDocument/definition", 8, 6, "");
+        assert_eq!(definition.get("result").get("range").get("start").get("line").as_usize(), Some(5));
+
+        let references = request(&mut server, "textDocument/references", 6, 6, "");
+        assert_eq!(references.get("result").as_array().unwrap().len(), 2);
+
+        let hover = request(&mut server, "textDocument/hover", 3, 6, "");
+        assert_eq!(
+            hover.get("result").get("contents").get("value").as_str(),
+            Some("`count`: variable at RAM[16]\n\nROM[2]: `0000000000010000`")
+        );
+
+        let completion = request(&mut server, "textDocument/completion", 9, 6, "");
+        assert_eq!(completion.get("result").as_array().unwrap().len(), 7);
+
+        let rename = request(&mut server, "textDocument/rename", 5, 2, r#","newName":"AGAIN""#);
+        let edits = rename.get("result").get("changes").get("untitled:1").as_array().unwrap();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[1].get("newText").as_str(), Some("AGAIN"));
+
+        let rename = request(&mut server, "textDocument/rename", 3, 6, r#","newName":"total""#);
+        assert_eq!(
+            rename.get("error").get("message").as_str(),
+            Some("only labels can be renamed, and `count` isn't one")
+        );
+    }
+
+    #[test]
+    fn test_rename_checks() {
+        // Labels passed to a macro are renamed along with the rest.
+        let source = ".macro GO target\n    @\\target\n    0;JMP\n.endm\n(LOOP)\n    GO LOOP // again\n";
+        let mut server = open("untitled:1", source);
+        let references = request(&mut server, "textDocument/references", 5, 8, "");
+        assert_eq!(references.get("result").as_array().unwrap().len(), 2);
+        let rename = request(&mut server, "textDocument/rename", 4, 2, r#","newName":"AGAIN""#);
+        let edits = rename.get("result").get("changes").get("untitled:1").as_array().unwrap();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[1].get("range").get("start").get("character").as_usize(), Some(7));
+
+        // A use in an included file can't be renamed, so nothing is.
+        let dir = std::env::temp_dir().join(format!("hack_lsp_rename_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("jump.asm"), "@LOOP\n0;JMP\n").unwrap();
+        let uri = format!("file://{}", dir.join("main.asm").display());
+        let mut server = open(&uri, "(LOOP)\n.include \"jump.asm\"\n");
+        let rename = request(&mut server, "textDocument/rename", 0, 2, r#","newName":"AGAIN""#);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            rename.get("error").get("message").as_str(),
+            Some("`LOOP` is used where it can't be renamed, such as in an included file")
+        );
+    }
+
+    #[test]
+    fn test_read_message() {
+        let mut input = Cursor::new("\r\nContent-Type: json\r\nContent-Length: 2\r\n\r\n{}");
+        assert_eq!(read_message(&mut input).unwrap().as_deref(), Some("{}"));
+        assert_eq!(read_message(&mut input).unwrap(), None);
+
+        for (message, error) in [
+            ("Content-Type: json\r\n\r\n{}", "message has no `Content-Length` header"),
+            ("Content-Length: two\r\n\r\n{}", "invalid `Content-Length` header `two`"),
+        ] {
+            assert_eq!(read_message(&mut Cursor::new(message)).unwrap_err().to_string(), error);
+        }
+    }
+
+    #[test]
+    fn test_uri_to_path() {
+        assert_eq!(uri_to_path("file:///home/me/My%20Prog.asm"), Some(PathBuf::from("/home/me/My Prog.asm")));
+        assert_eq!(uri_to_path("file:///C:/Prog.asm"), Some(PathBuf::from("C:/Prog.asm")));
+        assert_eq!(uri_to_path("untitled:1"), None);
+    }
+}
@@ -1,12 +1,12 @@
-use hack_assembler::{match_args, run};
+use hack_assembler::{match_args, run_all};
 
 fn main() {
-    let config = match_args().unwrap_or_else(|err| {
+    let configs = match_args().unwrap_or_else(|err| {
         eprintln!("Error parsing arguments: {}", err);
         std::process::exit(1);
     });
 
-    if let Err(err) = run(config) {
+    if let Err(err) = run_all(configs) {
         eprintln!("Error running the Hack Assembler: {}", err);
         std::process::exit(1);
     }
@@ -0,0 +1,296 @@
+use crate::assembler::ParsedLine;
+use crate::instruction::{CInstruction, Comp, Dest, Instruction, Jump};
+use crate::{SymbolKind, SymbolTable};
+
+// What an A-instruction loads, as far as the optimizer can tell. Labels are
+// compared by name because removing code moves them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Load {
+    Value(u16),
+    Label(String),
+}
+
+fn load(instruction: &Instruction, symbol_table: &SymbolTable) -> Option<Load> {
+    match instruction {
+        Instruction::A(a_instruction) => Some(Load::Value(a_instruction.value())),
+        Instruction::Variable(name) => {
+            let symbol = symbol_table.get(name)?;
+            match symbol.kind {
+                SymbolKind::Label => Some(Load::Label(name.clone())),
+                _ => Some(Load::Value(symbol.address)),
+            }
+        }
+        Instruction::Expression(expr) => {
+            let value = expr.evaluate(symbol_table, 0).ok()?;
+            Some(Load::Value(u16::try_from(value).ok()?))
+        }
+        Instruction::C(_) | Instruction::L(_) | Instruction::Constant(..) => None,
+    }
+}
+
+// The first instruction that depends on where code is in ROM, and why:
+// `@LOOP+2` does arithmetic on a label, and a jump through an address built
+// from numbers alone, such as `@133` `0;JMP` or `@5` `D=A` `A=D;JMP`, goes to
+// a fixed address. Removing instructions would move the code they point at,
+// so such programs aren't optimized.
+pub fn fixed_address<'a>(
+    parsed_lines: &'a [ParsedLine],
+    symbol_table: &SymbolTable,
+) -> Option<(&'a ParsedLine, &'static str)> {
+    let is_label = |name: &&str| symbol_table.get(name).is_some_and(|symbol| symbol.kind == SymbolKind::Label);
+    // Whether A and D hold a number that doesn't depend on where code is.
+    // Nothing is known at a label, since execution may arrive from anywhere.
+    let (mut a_fixed, mut d_fixed) = (false, false);
+    for parsed in parsed_lines {
+        match &parsed.instruction {
+            Instruction::Expression(expr) if expr.symbols().iter().any(is_label) => {
+                return Some((parsed, "computes an address from a label"));
+            }
+            Instruction::C(c_instruction) => {
+                if c_instruction.jump() != Jump::Null && a_fixed {
+                    return Some((parsed, "jumps to a fixed address"));
+                }
+                let fixed = computes_fixed(c_instruction.comp(), a_fixed, d_fixed);
+                let dest = c_instruction.dest().mnemonic();
+                if dest.contains('A') {
+                    a_fixed = fixed;
+                }
+                if dest.contains('D') {
+                    d_fixed = fixed;
+                }
+            }
+            Instruction::L(_) => (a_fixed, d_fixed) = (false, false),
+            Instruction::Constant(..) => {}
+            instruction => a_fixed = matches!(load(instruction, symbol_table), Some(Load::Value(_))),
+        }
+    }
+    None
+}
+
+// Whether `comp` only reads registers that hold fixed numbers. RAM is never
+// fixed, and the ALU's zx and zy bits say whether D and A (or M) are read.
+fn computes_fixed(comp: Comp, a_fixed: bool, d_fixed: bool) -> bool {
+    let bits = comp.convert_to_binary();
+    let reads_d = bits & 0b100000 == 0;
+    let reads_a_or_m = bits & 0b001000 == 0;
+    (!reads_d || d_fixed) && (!reads_a_or_m || (a_fixed && !comp.uses_m()))
+}
+
+// Removes instructions that can't affect the program until nothing changes,
+// then moves every label to its new address. Returns the number of words
+// removed.
+pub fn optimize(parsed_lines: &mut Vec<ParsedLine>, symbol_table: &mut SymbolTable) -> usize {
+    let before = rom_size(parsed_lines);
+    loop {
+        let mut changed = remove_jumps_to_next(parsed_lines, symbol_table);
+        changed |= remove_no_ops(parsed_lines);
+        changed |= remove_dead_code(parsed_lines);
+        changed |= remove_redundant_loads(parsed_lines, symbol_table);
+        if !changed {
+            break;
+        }
+    }
+
+    let mut address = 0;
+    for parsed in parsed_lines.iter() {
+        match &parsed.instruction {
+            Instruction::L(label) => symbol_table.move_label(label, address),
+            instruction if instruction.occupies_rom() => address += 1,
+            _ => {}
+        }
+    }
+    before - rom_size(parsed_lines)
+}
+
+fn rom_size(parsed_lines: &[ParsedLine]) -> usize {
+    parsed_lines.iter().filter(|parsed| parsed.instruction.occupies_rom()).count()
+}
+
+// Drops the lines at the given indices, which are in ascending order.
+fn remove(parsed_lines: &mut Vec<ParsedLine>, indices: &[usize]) -> bool {
+    let mut index = 0;
+    let mut removed = indices.iter().peekable();
+    parsed_lines.retain(|_| {
+        let keep = removed.next_if_eq(&&index).is_none();
+        index += 1;
+        keep
+    });
+    !indices.is_empty()
+}
+
+// `@NEXT` `0;JMP` `(NEXT)`: the jump goes where execution was going anyway.
+// Only the jump is removed, since the code at the label may read A; the load
+// goes too if nothing uses it.
+fn remove_jumps_to_next(parsed_lines: &mut [ParsedLine], symbol_table: &SymbolTable) -> bool {
+    let mut changed = false;
+    for index in 1..parsed_lines.len() {
+        let Instruction::C(c_instruction) = &parsed_lines[index].instruction else {
+            continue;
+        };
+        if c_instruction.jump() == Jump::Null {
+            continue;
+        }
+        let Some(Load::Label(target)) = load(&parsed_lines[index - 1].instruction, symbol_table) else {
+            continue;
+        };
+        let falls_into_target = parsed_lines[index + 1..]
+            .iter()
+            .take_while(|parsed| !parsed.instruction.occupies_rom())
+            .any(|parsed| matches!(&parsed.instruction, Instruction::L(label) if *label == target));
+        if falls_into_target {
+            let c_instruction = CInstruction::new(c_instruction.dest(), c_instruction.comp(), Jump::Null);
+            parsed_lines[index].instruction = Instruction::C(c_instruction);
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Computations that go nowhere, like `D` on its own, and copies of a
+// register to itself, like `D=D`.
+fn remove_no_ops(parsed_lines: &mut Vec<ParsedLine>) -> bool {
+    let indices: Vec<usize> = parsed_lines
+        .iter()
+        .enumerate()
+        .filter(|(_, parsed)| match &parsed.instruction {
+            Instruction::C(c_instruction) => {
+                c_instruction.jump() == Jump::Null
+                    && matches!(
+                        (c_instruction.dest(), c_instruction.comp()),
+                        (Dest::Null, _) | (Dest::D, Comp::D) | (Dest::A, Comp::A) | (Dest::M, Comp::M)
+                    )
+            }
+            _ => false,
+        })
+        .map(|(index, _)| index)
+        .collect();
+    remove(parsed_lines, &indices)
+}
+
+// Instructions after an unconditional jump that no label leads to.
+fn remove_dead_code(parsed_lines: &mut Vec<ParsedLine>) -> bool {
+    let mut indices = Vec::new();
+    let mut reachable = true;
+    for (index, parsed) in parsed_lines.iter().enumerate() {
+        match &parsed.instruction {
+            Instruction::L(_) => reachable = true,
+            Instruction::Constant(..) => {}
+            _ if !reachable => indices.push(index),
+            Instruction::C(c_instruction) if c_instruction.jump() == Jump::JMP => reachable = false,
+            _ => {}
+        }
+    }
+    remove(parsed_lines, &indices)
+}
+
+// A load that is overwritten by the next one, as in `@X` `@Y`, or that loads
+// what A already holds, as in `@X` `M=D` `@X`.
+fn remove_redundant_loads(parsed_lines: &mut Vec<ParsedLine>, symbol_table: &SymbolTable) -> bool {
+    let mut indices = Vec::new();
+    // The last load, while A still holds it.
+    let mut holding: Option<Load> = None;
+    // The last load, until something reads A.
+    let mut unread: Option<usize> = None;
+    for (index, parsed) in parsed_lines.iter().enumerate() {
+        match &parsed.instruction {
+            // Code can jump here with anything in A.
+            Instruction::L(_) => holding = None,
+            Instruction::Constant(..) => {}
+            Instruction::C(c_instruction) => {
+                unread = None;
+                if c_instruction.dest() as u16 & Dest::A as u16 != 0 {
+                    holding = None;
+                }
+            }
+            instruction => {
+                let loaded = load(instruction, symbol_table);
+                if loaded.is_some() && holding == loaded {
+                    indices.push(index);
+                    continue;
+                }
+                if let Some(previous) = unread {
+                    indices.push(previous);
+                }
+                unread = Some(index);
+                holding = loaded;
+            }
+        }
+    }
+    indices.sort_unstable();
+    remove(parsed_lines, &indices)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::Options;
+    use std::io::Cursor;
+
+    fn optimized(source: &str) -> (Vec<u16>, usize) {
+        let options = Options {
+            optimize: true,
+            ..Options::default()
+        };
+        let mut assembler =
+            Assembler::new(Cursor::new(source), Vec::new(), SymbolTable::default()).with_options(options);
+        assembler.assemble().unwrap();
+        let saved = assembler.words_saved();
+        (assembler.into_program().words, saved)
+    }
+
+    #[test]
+    fn test_optimize() {
+        let source = "\
+@x
+@i
+D=M
+D=D
+@i
+M=D+1
+@NEXT
+0;JMP
+D=1
+@x
+(NEXT)
+@NEXT
+D;JGT
+(END)
+@END
+0;JMP
+";
+        let (words, saved) = optimized(source);
+        // @i D=M M=D+1 (NEXT) @NEXT D;JGT (END) @END 0;JMP
+        assert_eq!(
+            words,
+            [17, 0b1111110000010000, 0b1110011111001000, 3, 0b1110001100000001, 5, 0b1110101010000111]
+        );
+        assert_eq!(saved, 7);
+    }
+
+    #[test]
+    fn test_labels_move() {
+        let source = "@i\n@i\nD=M\n(LOOP)\n@LOOP\nD;JGT\n";
+        let (words, saved) = optimized(source);
+        assert_eq!(words, [16, 0b1111110000010000, 2, 0b1110001100000001]);
+        assert_eq!(saved, 1);
+    }
+
+    #[test]
+    fn test_fixed_addresses() {
+        for source in [
+            "@END+1\n@x\n@x\nD=M\n(END)\n",
+            "@4\n0;JMP\n@x\n@x\nD=M\n",
+            "@5\nD=A\n@x\nM=D\nA=D;JMP\n@x\n",
+            "@2\nD=A\n@3\nAD=D+A\n0;JMP\n@x\n",
+        ] {
+            let (words, saved) = optimized(source);
+            assert_eq!(words.len(), source.lines().filter(|line| !line.starts_with('(')).count());
+            assert_eq!(saved, 0);
+        }
+
+        // An address read from RAM, or known only through a label, can move.
+        let (_, saved) = optimized("(TOP)\n@TOP\nD=A\n@x\nM=D\nA=M\n0;JMP\n@x\n@x\n");
+        assert!(saved > 0);
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::{self, Write};
+
+// Module/entity name used by the HDL formats.
+const ROM_NAME: &str = "hack_rom";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    // One 16-digit binary word per line, as read by the course tools and `$readmemb`.
+    Hack,
+    // Raw big-endian image, two bytes per word.
+    Binary,
+    // One 4-digit hex word per line, as read by `$readmemh`.
+    Hex,
+    IntelHex,
+    // Logisim ROM/RAM image (`v2.0 raw`).
+    Logisim,
+    Verilog,
+    Vhdl,
+}
+
+impl OutputFormat {
+    pub const NAMES: [&'static str; 7] = ["hack", "bin", "hex", "ihex", "logisim", "verilog", "vhdl"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hack" => Some(OutputFormat::Hack),
+            "bin" => Some(OutputFormat::Binary),
+            "hex" => Some(OutputFormat::Hex),
+            "ihex" => Some(OutputFormat::IntelHex),
+            "logisim" => Some(OutputFormat::Logisim),
+            "verilog" => Some(OutputFormat::Verilog),
+            "vhdl" => Some(OutputFormat::Vhdl),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Hack => "hack",
+            OutputFormat::Binary => "bin",
+            OutputFormat::Hex => "hex",
+            OutputFormat::IntelHex => "ihex",
+            OutputFormat::Logisim => "logisim",
+            OutputFormat::Verilog => "v",
+            OutputFormat::Vhdl => "vhd",
+        }
+    }
+
+    pub fn write<W: Write>(&self, words: &[u16], mut writer: W) -> io::Result<()> {
+        match self {
+            OutputFormat::Hack => {
+                for word in words {
+                    writeln!(writer, "{:016b}", word)?;
+                }
+            }
+            OutputFormat::Binary => {
+                for word in words {
+                    writer.write_all(&word.to_be_bytes())?;
+                }
+            }
+            OutputFormat::Hex => {
+                for word in words {
+                    writeln!(writer, "{:04x}", word)?;
+                }
+            }
+            OutputFormat::IntelHex => write_intel_hex(words, &mut writer)?,
+            OutputFormat::Logisim => {
+                writeln!(writer, "v2.0 raw")?;
+                for row in words.chunks(8) {
+                    let row: Vec<String> = row.iter().map(|word| format!("{:x}", word)).collect();
+                    writeln!(writer, "{}", row.join(" "))?;
+                }
+            }
+            OutputFormat::Verilog => write_verilog(words, &mut writer)?,
+            OutputFormat::Vhdl => write_vhdl(words, &mut writer)?,
+        }
+        writer.flush()
+    }
+}
+
+// Data records of up to 16 bytes addressed in bytes, so the full 32K-word ROM
+// fits in the 16-bit address space without extended address records.
+fn write_intel_hex<W: Write>(words: &[u16], writer: &mut W) -> io::Result<()> {
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+    for (index, chunk) in bytes.chunks(16).enumerate() {
+        let address = (index * 16) as u16;
+        let mut record = vec![chunk.len() as u8];
+        record.extend(address.to_be_bytes());
+        record.push(0x00);
+        record.extend(chunk);
+        write_intel_hex_record(&record, writer)?;
+    }
+    write_intel_hex_record(&[0x00, 0x00, 0x00, 0x01], writer)
+}
+
+fn write_intel_hex_record<W: Write>(record: &[u8], writer: &mut W) -> io::Result<()> {
+    let checksum = record
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg();
+    write!(writer, ":")?;
+    for byte in record {
+        write!(writer, "{:02X}", byte)?;
+    }
+    writeln!(writer, "{:02X}", checksum)
+}
+
+fn write_verilog<W: Write>(words: &[u16], writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "module {} (", ROM_NAME)?;
+    writeln!(writer, "    input  wire [14:0] address,")?;
+    writeln!(writer, "    output reg  [15:0] instruction")?;
+    writeln!(writer, ");")?;
+    writeln!(writer, "    always @(*) begin")?;
+    writeln!(writer, "        case (address)")?;
+    for (address, word) in words.iter().enumerate() {
+        writeln!(writer, "            15'd{}: instruction = 16'b{:016b};", address, word)?;
+    }
+    writeln!(writer, "            default: instruction = 16'b0;")?;
+    writeln!(writer, "        endcase")?;
+    writeln!(writer, "    end")?;
+    writeln!(writer, "endmodule")
+}
+
+fn write_vhdl<W: Write>(words: &[u16], writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "library ieee;")?;
+    writeln!(writer, "use ieee.std_logic_1164.all;")?;
+    writeln!(writer, "use ieee.numeric_std.all;")?;
+    writeln!(writer)?;
+    writeln!(writer, "entity {} is", ROM_NAME)?;
+    writeln!(writer, "    port (")?;
+    writeln!(writer, "        address     : in  std_logic_vector(14 downto 0);")?;
+    writeln!(writer, "        instruction : out std_logic_vector(15 downto 0)")?;
+    writeln!(writer, "    );")?;
+    writeln!(writer, "end entity {};", ROM_NAME)?;
+    writeln!(writer)?;
+    writeln!(writer, "architecture rtl of {} is", ROM_NAME)?;
+    writeln!(writer, "    type rom_t is array (0 to 32767) of std_logic_vector(15 downto 0);")?;
+    writeln!(writer, "    constant ROM : rom_t := (")?;
+    for (address, word) in words.iter().enumerate() {
+        writeln!(writer, "        {} => \"{:016b}\",", address, word)?;
+    }
+    writeln!(writer, "        others => (others => '0')")?;
+    writeln!(writer, "    );")?;
+    writeln!(writer, "begin")?;
+    writeln!(writer, "    instruction <= ROM(to_integer(unsigned(address)));")?;
+    writeln!(writer, "end architecture rtl;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn render(format: OutputFormat, words: &[u16]) -> Vec<u8> {
+        let mut output = Vec::new();
+        format.write(words, &mut output).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_text_formats() {
+        let words = [0x0010, 0xEA88];
+        assert_eq!(render(OutputFormat::Hack, &words), b"0000000000010000\n1110101010001000\n");
+        assert_eq!(render(OutputFormat::Hex, &words), b"0010\nea88\n");
+        assert_eq!(render(OutputFormat::Binary, &words), [0x00, 0x10, 0xEA, 0x88]);
+        assert_eq!(render(OutputFormat::Logisim, &words), b"v2.0 raw\n10 ea88\n");
+    }
+
+    #[test]
+    fn test_intel_hex() {
+        let words = [0x0010, 0xEA88];
+        assert_eq!(
+            String::from_utf8(render(OutputFormat::IntelHex, &words)).unwrap(),
+            ":040000000010EA887A\n:00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn test_hdl_formats() {
+        let words = [0x0010];
+        let verilog = String::from_utf8(render(OutputFormat::Verilog, &words)).unwrap();
+        assert!(verilog.contains("15'd0: instruction = 16'b0000000000010000;"));
+        let vhdl = String::from_utf8(render(OutputFormat::Vhdl, &words)).unwrap();
+        assert!(vhdl.contains("0 => \"0000000000010000\","));
+    }
+}
@@ -0,0 +1,688 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use crate::assembler::sanitize_line;
+use crate::error::ErrorCode;
+use crate::formatter::is_macro_invocation;
+use crate::lexer::{is_symbol, is_symbol_char};
+use crate::AsmError;
+
+// Deep enough for any sensible nesting, shallow enough to stop a macro that
+// invokes itself before the expansion gets out of hand.
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+// A line of source after preprocessing. `file` is set for lines that came
+// from an included file, and `line` counts from the start of that file.
+// Lines produced by a macro or an include carry the chain of invocations
+// that produced them in `expansions`, innermost first.
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub text: String,
+    pub file: Option<PathBuf>,
+    pub line: usize,
+    pub expansions: Vec<Expansion>,
+}
+
+// Where a macro was invoked, or with no macro name, where a file was included.
+#[derive(Debug, Clone)]
+pub struct Expansion {
+    pub macro_name: Option<String>,
+    pub file: Option<PathBuf>,
+    pub line: usize,
+}
+
+impl SourceLine {
+    // The line in the file being assembled: the outermost call site or
+    // `.include` for lines that came from elsewhere.
+    pub fn origin_line(&self) -> usize {
+        self.expansions.last().map_or(self.line, |expansion| expansion.line)
+    }
+
+    // The instruction on the line, without its comment or indentation.
+    pub fn code(&self) -> &str {
+        self.text.split("//").next().unwrap_or_default().trim()
+    }
+
+    pub fn annotate(&self, mut error: AsmError) -> AsmError {
+        if let Some(file) = &self.file {
+            error = error.with_file(file.clone());
+        }
+        for expansion in &self.expansions {
+            let at = location(expansion.file.as_deref(), expansion.line);
+            error = error.with_note(match &expansion.macro_name {
+                Some(name) => format!("in expansion of macro `{}` invoked at {}", name, at),
+                None => format!("included from {}", at),
+            });
+        }
+        error
+    }
+
+    pub fn error<S: Into<String>>(&self, code: ErrorCode, message: S, needle: &str) -> AsmError {
+        self.annotate(
+            AsmError::error(code, self.line, message)
+                .with_span(span_of(&self.text, needle))
+                .with_source(self.text.as_str()),
+        )
+    }
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    // Body lines as written, with the file and line they came from.
+    body: Vec<SourceLine>,
+    // Labels defined in the body; each expansion gets its own copy.
+    labels: HashSet<String>,
+}
+
+pub struct Preprocessor {
+    macros: HashMap<String, Macro>,
+    expansion_count: usize,
+    include_paths: Vec<PathBuf>,
+    // The file being assembled, if it is a file.
+    file: Option<PathBuf>,
+    // Canonical paths of the files currently being included, outermost first.
+    include_stack: Vec<PathBuf>,
+    pub errors: Vec<AsmError>,
+}
+
+impl Preprocessor {
+    pub fn new() -> Self {
+        Preprocessor {
+            macros: HashMap::new(),
+            expansion_count: 0,
+            include_paths: Vec::new(),
+            file: None,
+            include_stack: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    // Directories searched, in order, for included files that aren't found
+    // next to the file including them.
+    pub fn with_include_paths(mut self, include_paths: Vec<PathBuf>) -> Self {
+        self.include_paths = include_paths;
+        self
+    }
+
+    // Handles `.include "path"`, collects `.macro NAME params ... .endm`
+    // definitions and replaces each invocation with the macro body. `file` is
+    // the path of the source, used to resolve relative includes.
+    pub fn process(&mut self, file: Option<&Path>, lines: Vec<(usize, String)>) -> Vec<SourceLine> {
+        self.file = file.map(Path::to_path_buf);
+        self.include_stack = file.and_then(|file| file.canonicalize().ok()).into_iter().collect();
+
+        let lines = lines
+            .into_iter()
+            .map(|(line, text)| SourceLine {
+                text,
+                file: None,
+                line,
+                expansions: Vec::new(),
+            })
+            .collect();
+        let mut output = Vec::new();
+        self.process_lines(lines, &mut output);
+        output
+    }
+
+    fn process_lines(&mut self, lines: Vec<SourceLine>, output: &mut Vec<SourceLine>) {
+        let mut lines = lines.into_iter();
+        while let Some(source_line) = lines.next() {
+            let code = code(&source_line.text);
+            if let Some(header) = directive(&code, ".macro") {
+                self.define(&source_line, header, &mut lines);
+            } else if let Some(path) = directive(&code, ".include") {
+                self.include(&source_line, path, output);
+            } else if directive(&code, ".endm").is_some() {
+                self.errors.push(source_line.error(
+                    ErrorCode::InvalidDirective,
+                    "`.endm` without a matching `.macro`",
+                    ".endm",
+                ));
+            } else if let Err(error) = self.expand_line(source_line, 0, output) {
+                self.errors.push(error);
+            }
+        }
+    }
+
+    fn include(&mut self, source_line: &SourceLine, path: &str, output: &mut Vec<SourceLine>) {
+        let Some(path) = path.strip_prefix('"').and_then(|path| path.strip_suffix('"')) else {
+            self.errors.push(
+                source_line
+                    .error(ErrorCode::InvalidDirective, "expected a quoted path after `.include`", path)
+                    .with_hint("write it as `.include \"file.asm\"`"),
+            );
+            return;
+        };
+        let including_file = source_line.file.as_deref().or(self.file.as_deref());
+        // Source from memory has no directory of its own, so only `-I` directories are searched.
+        if including_file.is_none() && self.include_paths.is_empty() {
+            self.errors.push(
+                source_line
+                    .error(ErrorCode::IncludeNotFound, format!("can't include `{}` without a source file", path), path)
+                    .with_hint("assemble from a file, or add directories to search with `-I`"),
+            );
+            return;
+        }
+        let Some(resolved) = self.resolve(path, including_file) else {
+            let hint = match self.include_paths.is_empty() {
+                true => "paths are relative to the including file; add directories to search with `-I`",
+                false => "paths are relative to the including file or one of the `-I` directories",
+            };
+            self.errors.push(
+                source_line
+                    .error(ErrorCode::IncludeNotFound, format!("can't find `{}` to include", path), path)
+                    .with_hint(hint),
+            );
+            return;
+        };
+
+        let canonical = resolved.canonicalize().unwrap_or_else(|_| resolved.clone());
+        if let Some(start) = self.include_stack.iter().position(|file| *file == canonical) {
+            let cycle: Vec<String> = self.include_stack[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|file| file.file_name().unwrap_or_default().to_string_lossy().into_owned())
+                .collect();
+            self.errors.push(source_line.error(
+                ErrorCode::IncludeCycle,
+                format!("include cycle: {}", cycle.join(" -> ")),
+                path,
+            ));
+            return;
+        }
+
+        let contents = match std::fs::read_to_string(&resolved) {
+            Ok(contents) => contents,
+            Err(e) => {
+                self.errors.push(source_line.error(
+                    ErrorCode::ReadFailure,
+                    format!("failed to read `{}`: {}", resolved.display(), e),
+                    path,
+                ));
+                return;
+            }
+        };
+
+        let mut expansions = vec![Expansion {
+            macro_name: None,
+            file: source_line.file.clone(),
+            line: source_line.line,
+        }];
+        expansions.extend(source_line.expansions.iter().cloned());
+        let lines = contents
+            .lines()
+            .enumerate()
+            .map(|(index, text)| SourceLine {
+                text: text.to_string(),
+                file: Some(resolved.clone()),
+                line: index + 1,
+                expansions: expansions.clone(),
+            })
+            .collect();
+
+        self.include_stack.push(canonical);
+        self.process_lines(lines, output);
+        self.include_stack.pop();
+    }
+
+    fn resolve(&self, path: &str, including_file: Option<&Path>) -> Option<PathBuf> {
+        let path = Path::new(path);
+        if path.is_absolute() {
+            return (including_file.is_some() && path.is_file()).then(|| path.to_path_buf());
+        }
+        let base = including_file.map(|file| file.parent().unwrap_or(Path::new("")));
+        base.into_iter()
+            .chain(self.include_paths.iter().map(PathBuf::as_path))
+            .map(|directory| directory.join(path))
+            .find(|candidate| candidate.is_file())
+    }
+
+    fn define<I>(&mut self, header_line: &SourceLine, header: &str, lines: &mut I)
+    where
+        I: Iterator<Item = SourceLine>,
+    {
+        let (name, parameters) = match header.split_once(char::is_whitespace) {
+            Some((name, parameters)) => (name, split_arguments(parameters)),
+            None => (header, Vec::new()),
+        };
+        let error = |code, message: String| header_line.error(code, message, header_line.text.trim());
+
+        let mut body = Vec::new();
+        let mut terminated = false;
+        for body_line in lines.by_ref() {
+            let body_code = code(&body_line.text);
+            if directive(&body_code, ".endm").is_some() {
+                terminated = true;
+                break;
+            }
+            if let Some(nested) = [".macro", ".include"]
+                .into_iter()
+                .find(|nested| directive(&body_code, nested).is_some())
+            {
+                self.errors.push(
+                    body_line
+                        .error(
+                            ErrorCode::InvalidDirective,
+                            format!("`{}` isn't allowed inside a macro", nested),
+                            nested,
+                        )
+                        .with_hint("move it to the top level; macros may still invoke each other"),
+                );
+                continue;
+            }
+            body.push(body_line);
+        }
+
+        if !terminated {
+            self.errors.push(error(
+                ErrorCode::InvalidDirective,
+                format!("macro `{}` is missing its `.endm`", name),
+            ));
+            return;
+        }
+        if name.is_empty() || !is_symbol(name) {
+            self.errors.push(error(
+                ErrorCode::InvalidDirective,
+                format!("invalid macro name `{}`", name),
+            ));
+            return;
+        }
+        if let Some(parameter) = parameters.iter().find(|parameter| !is_symbol(parameter)) {
+            self.errors.push(error(
+                ErrorCode::InvalidDirective,
+                format!("invalid macro parameter `{}`", parameter),
+            ));
+            return;
+        }
+        if self.macros.contains_key(name) {
+            self.errors.push(error(
+                ErrorCode::DuplicateMacro,
+                format!("macro `{}` is already defined", name),
+            ));
+            return;
+        }
+
+        let labels = body
+            .iter()
+            .filter_map(|body_line| {
+                let code = code(&body_line.text);
+                let label = code.strip_prefix('(')?.strip_suffix(')')?;
+                is_symbol(label).then(|| label.to_string())
+            })
+            .collect();
+        self.macros.insert(
+            name.to_string(),
+            Macro {
+                parameters,
+                body,
+                labels,
+            },
+        );
+    }
+
+    // Expands `source_line` if it invokes a macro. Runaway recursion abandons
+    // the whole expansion, since a macro invoking itself more than once would
+    // otherwise take exponential time to reach the depth limit everywhere.
+    #[allow(clippy::result_large_err)]
+    fn expand_line(
+        &mut self,
+        source_line: SourceLine,
+        depth: usize,
+        output: &mut Vec<SourceLine>,
+    ) -> Result<(), AsmError> {
+        let code = code(&source_line.text);
+        let (name, arguments) = match code.split_once(char::is_whitespace) {
+            Some((name, arguments)) => (name, split_arguments(arguments)),
+            None => (code.as_str(), Vec::new()),
+        };
+        let Some(definition) = self.macros.get(name) else {
+            output.push(source_line);
+            return Ok(());
+        };
+
+        let error = |code, message: String| source_line.error(code, message, source_line.text.trim());
+        if depth >= MAX_EXPANSION_DEPTH {
+            return Err(error(
+                ErrorCode::MacroRecursion,
+                format!("macro `{}` expands too deeply; does it invoke itself?", name),
+            ));
+        }
+        if arguments.len() != definition.parameters.len() {
+            self.errors.push(error(
+                ErrorCode::MacroArguments,
+                format!(
+                    "macro `{}` takes {} argument(s) but {} were given",
+                    name,
+                    definition.parameters.len(),
+                    arguments.len()
+                ),
+            ));
+            return Ok(());
+        }
+
+        self.expansion_count += 1;
+        let suffix = self.expansion_count;
+        let bindings: HashMap<&str, &str> = definition
+            .parameters
+            .iter()
+            .map(String::as_str)
+            .zip(arguments.iter().map(String::as_str))
+            .collect();
+
+        let mut expansions = vec![Expansion {
+            macro_name: Some(name.to_string()),
+            file: source_line.file.clone(),
+            line: source_line.line,
+        }];
+        expansions.extend(source_line.expansions.iter().cloned());
+
+        let mut expanded = Vec::new();
+        for body_line in &definition.body {
+            let body_line = SourceLine {
+                expansions: expansions.clone(),
+                ..body_line.clone()
+            };
+            let localized = rename_symbols(&body_line.text, |identifier| {
+                definition
+                    .labels
+                    .contains(identifier)
+                    .then(|| format!("{}${}${}", name, identifier, suffix))
+            });
+            match substitute(&localized, &bindings) {
+                Ok(text) => expanded.push(SourceLine { text, ..body_line }),
+                Err(parameter) => self.errors.push(body_line.error(
+                    ErrorCode::MacroArguments,
+                    format!("macro `{}` has no parameter `{}`", name, parameter),
+                    &format!("\\{}", parameter),
+                )),
+            }
+        }
+
+        for line in expanded {
+            self.expand_line(line, depth + 1, output)?;
+        }
+        Ok(())
+    }
+}
+
+fn location(file: Option<&Path>, line: usize) -> String {
+    match file {
+        Some(file) => format!("{}:{}", file.display(), line),
+        None => format!("line {}", line),
+    }
+}
+
+impl Default for Preprocessor {
+    fn default() -> Self {
+        Preprocessor::new()
+    }
+}
+
+// The line with comments and surrounding whitespace removed.
+fn code(text: &str) -> String {
+    sanitize_line(text, 0).ok().flatten().unwrap_or_default()
+}
+
+// Returns the rest of the line if it starts with the given directive.
+fn directive<'a>(code: &'a str, name: &str) -> Option<&'a str> {
+    let rest = code.strip_prefix(name)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+fn split_arguments(arguments: &str) -> Vec<String> {
+    if arguments.trim().is_empty() {
+        return Vec::new();
+    }
+    arguments
+        .split(',')
+        .map(|argument| argument.trim().to_string())
+        .collect()
+}
+
+fn span_of(text: &str, needle: &str) -> std::ops::Range<usize> {
+    let start = text.find(needle).unwrap_or(0);
+    start..start + needle.len()
+}
+
+// Applies `rename` to the symbols a line refers to: the operand of `@`, the
+// name in `(LABEL)` and the arguments of a macro invocation. Mnemonics,
+// registers and comments are left alone, so a label named `D` can't turn
+// `D=M` into something else.
+fn rename_symbols<F>(text: &str, rename: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let (code, comment) = text.split_at(text.find("//").unwrap_or(text.len()));
+    let indent = code.len() - code.trim_start().len();
+    let trimmed = code.trim();
+    let start = if trimmed.starts_with(['@', '(']) {
+        indent + 1
+    } else if is_macro_invocation(trimmed) {
+        indent + trimmed.find(char::is_whitespace).unwrap_or(trimmed.len())
+    } else {
+        return text.to_string();
+    };
+    format!("{}{}{}", &code[..start], rename_identifiers(&code[start..], rename), comment)
+}
+
+// Applies `rename` to every identifier in `text` that isn't a `\parameter`.
+fn rename_identifiers<F>(text: &str, rename: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if !is_symbol_char(c) {
+            result.push(c);
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        while let Some(&(index, next)) = chars.peek() {
+            if !is_symbol_char(next) {
+                break;
+            }
+            end = index + next.len_utf8();
+            chars.next();
+        }
+        let identifier = &text[start..end];
+        let is_parameter = text[..start].ends_with('\\');
+        match rename(identifier) {
+            Some(renamed) if !is_parameter => result.push_str(&renamed),
+            _ => result.push_str(identifier),
+        }
+    }
+    result
+}
+
+// Replaces each `\parameter` with its argument, failing on unknown names.
+fn substitute(text: &str, bindings: &HashMap<&str, &str>) -> Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find('\\') {
+        result.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+        let length = after.find(|c: char| !is_symbol_char(c)).unwrap_or(after.len());
+        let parameter = &after[..length];
+        match bindings.get(parameter) {
+            Some(argument) => result.push_str(argument),
+            None => return Err(parameter.to_string()),
+        }
+        rest = &after[length..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(source: &str) -> Vec<(usize, String)> {
+        source
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.to_string()))
+            .collect()
+    }
+
+    fn texts(source_lines: &[SourceLine]) -> Vec<&str> {
+        source_lines.iter().map(|line| line.text.trim()).collect()
+    }
+
+    #[test]
+    fn test_expansion() {
+        let source = "\
+.macro SET addr, value
+  @\\value
+  D=A
+  @\\addr
+  M=D
+.endm
+SET R1, 5
+SET x, 7";
+        let mut preprocessor = Preprocessor::new();
+        let output = preprocessor.process(None, lines(source));
+
+        assert!(preprocessor.errors.is_empty());
+        assert_eq!(
+            texts(&output),
+            ["@5", "D=A", "@R1", "M=D", "@7", "D=A", "@x", "M=D"]
+        );
+        assert_eq!(output[0].line, 2);
+        assert_eq!(output[0].origin_line(), 7);
+        assert_eq!(output[4].origin_line(), 8);
+    }
+
+    #[test]
+    fn test_local_labels_and_nesting() {
+        let source = "\
+.macro WAIT
+(LOOP)
+  @LOOP
+  0;JMP
+.endm
+.macro TWICE
+WAIT
+WAIT
+.endm
+TWICE
+@LOOP";
+        let mut preprocessor = Preprocessor::new();
+        let output = preprocessor.process(None, lines(source));
+
+        assert!(preprocessor.errors.is_empty());
+        assert_eq!(
+            texts(&output),
+            [
+                "(WAIT$LOOP$2)",
+                "@WAIT$LOOP$2",
+                "0;JMP",
+                "(WAIT$LOOP$3)",
+                "@WAIT$LOOP$3",
+                "0;JMP",
+                "@LOOP"
+            ]
+        );
+        assert_eq!(output[0].expansions.len(), 2);
+        assert_eq!(output[0].expansions[1].line, 10);
+    }
+
+    #[test]
+    fn test_local_labels_named_like_registers() {
+        let source = "\
+.macro SKIP
+  @D // to D
+  D;JEQ
+(D)
+  D=M
+.endm
+SKIP";
+        let mut preprocessor = Preprocessor::new();
+        let output = preprocessor.process(None, lines(source));
+
+        assert!(preprocessor.errors.is_empty());
+        assert_eq!(texts(&output), ["@SKIP$D$1 // to D", "D;JEQ", "(SKIP$D$1)", "D=M"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let source = "\
+.macro LOOP
+LOOP
+.endm
+.macro ONE a
+@\\b
+.endm
+LOOP
+ONE
+ONE 1
+.endm
+.macro OPEN";
+        let mut preprocessor = Preprocessor::new();
+        preprocessor.process(None, lines(source));
+
+        let codes: Vec<ErrorCode> = preprocessor.errors.iter().map(|e| e.code).collect();
+        assert_eq!(
+            codes,
+            [
+                ErrorCode::MacroRecursion,
+                ErrorCode::MacroArguments,
+                ErrorCode::MacroArguments,
+                ErrorCode::InvalidDirective,
+                ErrorCode::InvalidDirective,
+            ]
+        );
+        // `@\b` is reported at the body line, with a note for the call site.
+        assert_eq!(preprocessor.errors[2].line, 5);
+        assert_eq!(preprocessor.errors[2].notes, ["in expansion of macro `ONE` invoked at line 9"]);
+    }
+
+    #[test]
+    fn test_branching_recursion() {
+        // Each expansion invokes the macro twice, so the depth limit is only
+        // reached quickly if the first recursion error stops everything.
+        let source = ".macro TWICE\nTWICE\nTWICE\n.endm\nTWICE\nTWICE";
+        let mut preprocessor = Preprocessor::new();
+        let output = preprocessor.process(None, lines(source));
+
+        assert!(output.is_empty());
+        let codes: Vec<ErrorCode> = preprocessor.errors.iter().map(|e| e.code).collect();
+        assert_eq!(codes, [ErrorCode::MacroRecursion, ErrorCode::MacroRecursion]);
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = std::env::temp_dir().join(format!("hack_assembler_include_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        std::fs::create_dir_all(dir.join("shared")).unwrap();
+        std::fs::write(dir.join("lib/set.asm"), ".macro SET addr\n@\\addr\nM=1\n.endm\n.include \"zero.asm\"\n").unwrap();
+        std::fs::write(dir.join("shared/zero.asm"), "D=0\n").unwrap();
+        std::fs::write(dir.join("loop.asm"), ".include \"main.asm\"\n").unwrap();
+        let main = dir.join("main.asm");
+        let source = ".include \"lib/set.asm\"\nSET R1\n.include \"loop.asm\"\n.include \"missing.asm\"";
+        std::fs::write(&main, source).unwrap();
+
+        let mut preprocessor = Preprocessor::new().with_include_paths(vec![dir.join("shared")]);
+        let output = preprocessor.process(Some(&main), lines(source));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(texts(&output), ["D=0", "@R1", "M=1"]);
+        assert_eq!(output[0].file, Some(dir.join("shared/zero.asm")));
+        assert_eq!(output[0].line, 1);
+        assert_eq!(output[0].origin_line(), 1);
+        assert_eq!(output[1].origin_line(), 2);
+
+        let codes: Vec<ErrorCode> = preprocessor.errors.iter().map(|e| e.code).collect();
+        assert_eq!(codes, [ErrorCode::IncludeCycle, ErrorCode::IncludeNotFound]);
+        assert_eq!(preprocessor.errors[0].message, "include cycle: main.asm -> loop.asm -> main.asm");
+        assert_eq!(preprocessor.errors[0].file, Some(dir.join("loop.asm")));
+        assert_eq!(preprocessor.errors[0].notes, ["included from line 3"]);
+        assert_eq!(preprocessor.errors[1].line, 4);
+    }
+}
@@ -0,0 +1,593 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::error::{AssemblyError, ErrorCode};
+use crate::{assemble_program, AsmError, Emulator, Options};
+
+// Runs Nand2Tetris CPU emulator test scripts (.tst). The program named by
+// `load` is assembled and run on the built-in `Emulator`; each `output` adds a
+// line to the .out file, which is compared with the .cmp file as it goes.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    text: String,
+    line: usize,
+    span: Range<usize>,
+    quoted: bool,
+}
+
+impl Token {
+    fn is(&self, punctuation: &str) -> bool {
+        !self.quoted && self.text == punctuation
+    }
+
+    fn is_separator(&self) -> bool {
+        self.is(",") || self.is(";") || self.is("!")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Ram(u16),
+    A,
+    D,
+    Pc,
+    Time,
+}
+
+// One column of an `output-list`, such as `RAM[0]%D2.6.2`: the value is
+// written in `width` characters with `left` and `right` spaces around it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Column {
+    name: String,
+    location: Location,
+    format: char,
+    left: usize,
+    width: usize,
+    right: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Command {
+    Load(Option<String>),
+    OutputFile(String),
+    CompareTo(String),
+    OutputList(Vec<Column>),
+    Set(Location, u16),
+    Tick,
+    Tock,
+    TickTock,
+    Output,
+    // `repeat n { ... }`; without a count the body runs until the step limit.
+    Repeat(Option<usize>, Vec<Statement>),
+    Echo(String),
+    ClearEcho,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Statement {
+    command: Command,
+    line: usize,
+}
+
+// The first output line that doesn't match the .cmp file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScriptResult {
+    pub output_file: Option<PathBuf>,
+    pub compare_file: Option<PathBuf>,
+    pub output: Vec<String>,
+    pub echoes: Vec<String>,
+    pub mismatch: Option<Mismatch>,
+}
+
+// Runs the script at `path`. Files it names are relative to the script, and
+// `max_steps` bounds `repeat` loops without a count.
+pub fn run_script(path: &Path, options: Options, max_steps: usize) -> Result<ScriptResult, AssemblyError> {
+    let source = std::fs::read_to_string(path)?;
+    let annotate = |errors: Vec<AsmError>| {
+        let errors = errors.into_iter().map(|error| error.with_file(path)).collect();
+        AssemblyError::AsmErrors(errors)
+    };
+    let statements = parse(&source).map_err(|error| annotate(vec![error]))?;
+
+    let mut runner = Runner {
+        directory: path.parent().unwrap_or(Path::new("")).to_path_buf(),
+        script: path.to_path_buf(),
+        options,
+        max_steps,
+        emulator: Emulator::new(Vec::new()),
+        time: 0,
+        half_tick: false,
+        columns: Vec::new(),
+        compare: None,
+        result: ScriptResult {
+            output_file: None,
+            compare_file: None,
+            output: Vec::new(),
+            echoes: Vec::new(),
+            mismatch: None,
+        },
+    };
+    runner.run(&statements)?;
+    if let Some(output_file) = &runner.result.output_file {
+        let mut contents = runner.result.output.join("\n");
+        contents.push('\n');
+        std::fs::write(output_file, contents)?;
+    }
+    Ok(runner.result)
+}
+
+#[allow(clippy::result_large_err)]
+fn tokenize(source: &str) -> Result<Vec<Token>, AsmError> {
+    let mut tokens = Vec::new();
+    let mut in_block_comment = false;
+    for (index, line) in source.lines().enumerate() {
+        let error = |message: &str, span: Range<usize>| {
+            AsmError::error(ErrorCode::InvalidScript, index + 1, message)
+                .with_span(span)
+                .with_source(line)
+        };
+        let mut chars = line.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            if in_block_comment {
+                if c == '*' && chars.next_if(|(_, next)| *next == '/').is_some() {
+                    in_block_comment = false;
+                }
+                continue;
+            }
+            let token = |text: String, end: usize, quoted: bool| Token {
+                text,
+                line: index + 1,
+                span: start..end,
+                quoted,
+            };
+            match c {
+                _ if c.is_whitespace() => {}
+                '/' if chars.next_if(|(_, next)| *next == '/').is_some() => break,
+                '/' if chars.next_if(|(_, next)| *next == '*').is_some() => in_block_comment = true,
+                ',' | ';' | '!' | '{' | '}' => tokens.push(token(c.to_string(), start + 1, false)),
+                '"' => {
+                    let Some(length) = line[start + 1..].find('"') else {
+                        return Err(error("unterminated string", start..line.len()));
+                    };
+                    let end = start + 1 + length;
+                    tokens.push(token(line[start + 1..end].to_string(), end + 1, true));
+                    while chars.next_if(|(index, _)| *index <= end).is_some() {}
+                }
+                _ => {
+                    let mut end = start + c.len_utf8();
+                    while let Some((index, next)) =
+                        chars.next_if(|(_, next)| !next.is_whitespace() && !",;!{}\"".contains(*next))
+                    {
+                        end = index + next.len_utf8();
+                    }
+                    tokens.push(token(line[start..end].to_string(), end, false));
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[allow(clippy::result_large_err)]
+fn parse(source: &str) -> Result<Vec<Statement>, AsmError> {
+    let tokens = tokenize(source)?;
+    let lines: Vec<&str> = source.lines().collect();
+    let mut parser = Parser {
+        tokens: &tokens,
+        lines: &lines,
+        position: 0,
+    };
+    parser.statements(None)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    lines: &'a [&'a str],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: String, token: &Token) -> AsmError {
+        AsmError::error(ErrorCode::InvalidScript, token.line, message)
+            .with_span(token.span.clone())
+            .with_source(self.lines[token.line - 1])
+    }
+
+    // Statements up to the end of the script, or up to the `}` closing the
+    // `{` given.
+    #[allow(clippy::result_large_err)]
+    fn statements(&mut self, open: Option<&Token>) -> Result<Vec<Statement>, AsmError> {
+        let mut statements = Vec::new();
+        loop {
+            let Some(token) = self.tokens.get(self.position) else {
+                return match open {
+                    Some(open) => Err(self.error("`{` is never closed".to_string(), open)),
+                    None => Ok(statements),
+                };
+            };
+            self.position += 1;
+            if token.is_separator() {
+                continue;
+            }
+            if token.is("}") {
+                return match open {
+                    Some(_) => Ok(statements),
+                    None => Err(self.error("unexpected `}`".to_string(), token)),
+                };
+            }
+
+            let mut arguments = Vec::new();
+            while let Some(argument) = self.tokens.get(self.position) {
+                if argument.is_separator() || argument.is("{") || argument.is("}") {
+                    break;
+                }
+                arguments.push(argument);
+                self.position += 1;
+            }
+            let command = self.command(token, &arguments)?;
+            statements.push(Statement {
+                command,
+                line: token.line,
+            });
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn command(&mut self, token: &Token, arguments: &[&Token]) -> Result<Command, AsmError> {
+        let expect = |count: usize| match arguments.len() == count {
+            true => Ok(()),
+            false => Err(self.error(
+                format!("`{}` takes {} argument(s) but {} were given", token.text, count, arguments.len()),
+                token,
+            )),
+        };
+        Ok(match token.text.as_str() {
+            "load" => match arguments {
+                [] | [_] => Command::Load(arguments.first().map(|argument| argument.text.clone())),
+                _ => return Err(self.error("`load` takes at most one file".to_string(), arguments[1])),
+            },
+            "output-file" => {
+                expect(1)?;
+                Command::OutputFile(arguments[0].text.clone())
+            }
+            "compare-to" => {
+                expect(1)?;
+                Command::CompareTo(arguments[0].text.clone())
+            }
+            "output-list" => Command::OutputList(
+                arguments
+                    .iter()
+                    .map(|argument| self.column(argument))
+                    .collect::<Result<_, _>>()?,
+            ),
+            "set" => {
+                expect(2)?;
+                let location = self.location(arguments[0], &arguments[0].text)?;
+                if location == Location::Time {
+                    return Err(self.error("`time` can't be set".to_string(), arguments[0]));
+                }
+                Command::Set(location, self.value(arguments[1])?)
+            }
+            "tick" => Command::Tick,
+            "tock" => Command::Tock,
+            "ticktock" => Command::TickTock,
+            "output" => Command::Output,
+            "echo" => {
+                expect(1)?;
+                Command::Echo(arguments[0].text.clone())
+            }
+            "clear-echo" => Command::ClearEcho,
+            "repeat" => {
+                let count = match arguments {
+                    [] => None,
+                    [count] => Some(
+                        count
+                            .text
+                            .parse()
+                            .map_err(|_| self.error(format!("invalid repeat count `{}`", count.text), count))?,
+                    ),
+                    _ => return Err(self.error("`repeat` takes at most one count".to_string(), arguments[1])),
+                };
+                let Some(open) = self.tokens.get(self.position).filter(|open| open.is("{")) else {
+                    return Err(self.error("expected `{` after `repeat`".to_string(), token));
+                };
+                self.position += 1;
+                Command::Repeat(count, self.statements(Some(open))?)
+            }
+            "while" => return Err(self.error("`while` isn't supported".to_string(), token)),
+            _ => return Err(self.error(format!("unknown script command `{}`", token.text), token)),
+        })
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn column(&self, token: &Token) -> Result<Column, AsmError> {
+        let (name, format) = token.text.split_once('%').unwrap_or((&token.text, "D1.6.1"));
+        let invalid = || {
+            self.error(format!("invalid output format `%{}`", format), token)
+                .with_hint("formats look like `%D2.6.2`: D, B, X or S, then left padding, width and right padding")
+        };
+        let mut chars = format.chars();
+        let kind = chars.next().filter(|kind| "DBXS".contains(*kind)).ok_or_else(invalid)?;
+        let sizes: Vec<usize> = chars
+            .as_str()
+            .split('.')
+            .map(|size| size.parse().map_err(|_| invalid()))
+            .collect::<Result<_, _>>()?;
+        let [left, width, right] = sizes[..] else {
+            return Err(invalid());
+        };
+        Ok(Column {
+            name: name.to_string(),
+            location: self.location(token, name)?,
+            format: kind,
+            left,
+            width,
+            right,
+        })
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn location(&self, token: &Token, name: &str) -> Result<Location, AsmError> {
+        match name {
+            "A" => return Ok(Location::A),
+            "D" => return Ok(Location::D),
+            "PC" => return Ok(Location::Pc),
+            "time" => return Ok(Location::Time),
+            _ => {}
+        }
+        name.strip_prefix("RAM[")
+            .and_then(|rest| rest.strip_suffix(']'))
+            .and_then(|address| address.parse::<u16>().ok())
+            .filter(|address| (*address as usize) < crate::emulator::RAM_SIZE)
+            .map(Location::Ram)
+            .ok_or_else(|| {
+                self.error(format!("unknown location `{}`", name), token)
+                    .with_hint("scripts can use `RAM[address]`, `A`, `D`, `PC` and `time`")
+            })
+    }
+
+    // Decimal, possibly negative, or `%D`, `%X` and `%B` prefixed.
+    #[allow(clippy::result_large_err)]
+    fn value(&self, token: &Token) -> Result<u16, AsmError> {
+        let text = token.text.as_str();
+        let (digits, radix) = match text.get(..2) {
+            Some("%D") => (&text[2..], 10),
+            Some("%X") => (&text[2..], 16),
+            Some("%B") => (&text[2..], 2),
+            _ => (text, 10),
+        };
+        i32::from_str_radix(digits, radix)
+            .ok()
+            .filter(|value| (-32768..=65535).contains(value))
+            .map(|value| value as u16)
+            .ok_or_else(|| self.error(format!("invalid value `{}`", text), token))
+    }
+}
+
+struct Runner {
+    directory: PathBuf,
+    script: PathBuf,
+    options: Options,
+    max_steps: usize,
+    emulator: Emulator,
+    time: usize,
+    half_tick: bool,
+    columns: Vec<Column>,
+    // The lines of the .cmp file.
+    compare: Option<Vec<String>>,
+    result: ScriptResult,
+}
+
+impl Runner {
+    // Returns early, without an error, at the first mismatch.
+    fn run(&mut self, statements: &[Statement]) -> Result<(), AssemblyError> {
+        for statement in statements {
+            if self.result.mismatch.is_some() {
+                return Ok(());
+            }
+            match &statement.command {
+                Command::Load(file) => self.load(file.as_deref(), statement.line)?,
+                Command::OutputFile(file) => self.result.output_file = Some(self.directory.join(file)),
+                Command::CompareTo(file) => {
+                    let path = self.directory.join(file);
+                    let contents = std::fs::read_to_string(&path)
+                        .map_err(|e| AssemblyError::Other(format!("failed to read {}: {}", path.display(), e).into()))?;
+                    self.compare = Some(contents.lines().map(str::to_string).collect());
+                    self.result.compare_file = Some(path);
+                }
+                Command::OutputList(columns) => {
+                    self.columns = columns.clone();
+                    let header = columns.iter().map(|column| {
+                        let total = column.left + column.width + column.right;
+                        let name: String = column.name.chars().take(total).collect();
+                        let padding = total - name.len();
+                        format!("{}{}{}", " ".repeat(padding / 2), name, " ".repeat(padding - padding / 2))
+                    });
+                    self.output(header.collect());
+                }
+                Command::Set(location, value) => match location {
+                    Location::Ram(address) => self.emulator.poke(*address, *value),
+                    Location::A => self.emulator.set_a(*value),
+                    Location::D => self.emulator.set_d(*value),
+                    Location::Pc => self.emulator.set_pc(*value),
+                    Location::Time => {}
+                },
+                Command::Tick => self.half_tick = true,
+                Command::Tock | Command::TickTock => {
+                    // A program that has run off the end of ROM just stays put.
+                    self.emulator.step();
+                    self.time += 1;
+                    self.half_tick = false;
+                }
+                Command::Output => {
+                    let values = self.columns.iter().map(|column| self.format(column)).collect();
+                    self.output(values);
+                }
+                Command::Repeat(Some(count), body) => {
+                    for _ in 0..*count {
+                        self.run(body)?;
+                    }
+                }
+                Command::Repeat(None, body) => loop {
+                    let time = self.time;
+                    self.run(body)?;
+                    if self.time == time || self.time >= self.max_steps || self.result.mismatch.is_some() {
+                        break;
+                    }
+                },
+                Command::Echo(text) => self.result.echoes.push(text.clone()),
+                Command::ClearEcho => self.result.echoes.clear(),
+            }
+        }
+        Ok(())
+    }
+
+    // Loads a .asm or .hack program, by default the one named after the script.
+    fn load(&mut self, file: Option<&str>, line: usize) -> Result<(), AssemblyError> {
+        let path = match file {
+            Some(file) => self.directory.join(file),
+            None => self.script.with_extension("hack"),
+        };
+        let reader = File::open(&path).map_err(|e| {
+            AsmError::error(
+                ErrorCode::InvalidScript,
+                line,
+                format!("failed to load {}: {}", path.display(), e),
+            )
+            .with_file(&self.script)
+        });
+        let reader = BufReader::new(reader.map_err(|error| AssemblyError::AsmErrors(vec![error]))?);
+        self.emulator = if path.extension().unwrap_or_default() == "asm" {
+            let program = assemble_program(reader, Some(&path), self.options.clone())
+                .map_err(|diagnostics| AssemblyError::AsmErrors(diagnostics.errors))?;
+            Emulator::new(program.words)
+        } else {
+            Emulator::from_hack(reader)?
+        };
+        self.time = 0;
+        self.half_tick = false;
+        Ok(())
+    }
+
+    fn format(&self, column: &Column) -> String {
+        let value = match column.location {
+            Location::Ram(address) => self.emulator.peek(address),
+            Location::A => self.emulator.a(),
+            Location::D => self.emulator.d(),
+            Location::Pc => self.emulator.pc(),
+            Location::Time => {
+                let time = format!("{}{}", self.time, if self.half_tick { "+" } else { "" });
+                return pad(column, &format!("{:<width$}", time, width = column.width));
+            }
+        };
+        let text = match column.format {
+            'B' => format!("{:0width$b}", value, width = column.width),
+            'X' => format!("{:0width$X}", value, width = column.width),
+            _ => format!("{:>width$}", value as i16, width = column.width),
+        };
+        // Too-wide values keep their low-order digits.
+        pad(column, &text[text.len().saturating_sub(column.width)..])
+    }
+
+    // Adds a line to the output and checks it against the .cmp file.
+    fn output(&mut self, cells: Vec<String>) {
+        let line = format!("|{}|", cells.join("|"));
+        let index = self.result.output.len();
+        if let Some(compare) = &self.compare {
+            let expected = compare.get(index).map_or("", String::as_str);
+            if !lines_match(&line, expected) {
+                self.result.mismatch = Some(Mismatch {
+                    line: index + 1,
+                    expected: expected.to_string(),
+                    actual: line.clone(),
+                });
+            }
+        }
+        self.result.output.push(line);
+    }
+}
+
+fn pad(column: &Column, text: &str) -> String {
+    format!("{}{}{}", " ".repeat(column.left), text, " ".repeat(column.right))
+}
+
+// Like the course's tools: lines must match character for character, padding
+// included, except that `*` in the .cmp file matches any character.
+fn lines_match(actual: &str, expected: &str) -> bool {
+    actual.chars().count() == expected.chars().count()
+        && actual.chars().zip(expected.chars()).all(|(a, e)| a == e || e == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let source = "\
+load Mult.asm,
+output-list RAM[0]%D2.6.2 time%S1.4.1; /* block
+comment */ set RAM[1] -1, // line comment
+repeat 2 {
+  ticktock;
+}
+echo \"a, b\";
+";
+        let statements = parse(source).unwrap();
+        let commands: Vec<&Command> = statements.iter().map(|statement| &statement.command).collect();
+        assert_eq!(commands.len(), 5);
+        assert_eq!(*commands[0], Command::Load(Some("Mult.asm".to_string())));
+        let Command::OutputList(columns) = commands[1] else {
+            panic!("expected an output list");
+        };
+        assert_eq!((columns[0].location, columns[0].left, columns[0].width), (Location::Ram(0), 2, 6));
+        assert_eq!(columns[1].location, Location::Time);
+        assert_eq!(*commands[2], Command::Set(Location::Ram(1), 0xFFFF));
+        assert_eq!(statements[3].line, 4);
+        assert_eq!(
+            *commands[3],
+            Command::Repeat(
+                Some(2),
+                vec![Statement {
+                    command: Command::TickTock,
+                    line: 5
+                }]
+            )
+        );
+        assert_eq!(*commands[4], Command::Echo("a, b".to_string()));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = parse("repeat 3 {\nticktock;\n").unwrap_err();
+        assert_eq!(error.message, "`{` is never closed");
+        assert_eq!((error.line, error.span.clone()), (1, 9..10));
+        let error = parse("set RAM[0] 1,\nticktok;").unwrap_err();
+        assert_eq!(error.message, "unknown script command `ticktok`");
+        let error = parse("output-list RAM[0]%Q1.2.3;").unwrap_err();
+        assert_eq!(error.message, "invalid output format `%Q1.2.3`");
+        let error = parse("set KBD 1;").unwrap_err();
+        assert_eq!(error.message, "unknown location `KBD`");
+    }
+
+    #[test]
+    fn test_lines_match() {
+        assert!(lines_match("|  RAM[0]  |", "|  RAM[0]  |"));
+        assert!(lines_match("|      12  |", "|******12**|"));
+        assert!(!lines_match("|      12  |", "|      13  |"));
+        // Padding is part of the format, so a column of the wrong width fails.
+        assert!(!lines_match("|  RAM[0]  |", "| RAM[0] |"));
+        assert!(!lines_match("|     12   |", "|      12  |"));
+        assert!(!lines_match("|      12  |", "| ** |"));
+    }
+}
@@ -0,0 +1,58 @@
+// "Did you mean" support for mnemonics and symbols.
+
+// Picks the candidate closest to `input`. A candidate with exactly the same
+// characters in another order (e.g. `DM` for `MD`) always wins; otherwise the
+// nearest one by edit distance is used if it's close enough to be plausible.
+pub(crate) fn closest<'a, I>(input: &str, candidates: I) -> Option<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut sorted_input: Vec<char> = input.chars().collect();
+    sorted_input.sort_unstable();
+    let max_distance = (input.chars().count() / 2).max(1);
+
+    let mut best: Option<(usize, &'a str)> = None;
+    for candidate in candidates {
+        if candidate == input {
+            continue;
+        }
+        let mut sorted_candidate: Vec<char> = candidate.chars().collect();
+        sorted_candidate.sort_unstable();
+        let distance = if sorted_candidate == sorted_input {
+            0
+        } else {
+            edit_distance(input, candidate)
+        };
+        if distance <= max_distance && best.is_none_or(|(best_distance, _)| distance < best_distance) {
+            best = Some((distance, candidate));
+        }
+    }
+    best.map(|(_, candidate)| candidate)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_closest() {
+        let jumps = ["JGT", "JEQ", "JGE", "JLT", "JNE", "JLE", "JMP"];
+        assert_eq!(closest("JUMP", jumps), Some("JMP"));
+        assert_eq!(closest("DM", ["M", "D", "MD", "AMD"]), Some("MD"));
+        assert_eq!(closest("XYZZY", jumps), None);
+    }
+}
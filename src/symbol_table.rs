@@ -1,8 +1,42 @@
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
 
+use crate::json::json_string;
+
+pub const SCREEN: u16 = 16384;
+pub const KBD: u16 = 24576;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SymbolKind {
+    Predefined,
+    Constant,
+    Label,
+    Variable,
+}
+
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            SymbolKind::Predefined => "predefined",
+            SymbolKind::Constant => "constant",
+            SymbolKind::Label => "label",
+            SymbolKind::Variable => "variable",
+        };
+        f.pad(kind)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub address: u16,
+    pub kind: SymbolKind,
+}
+
+#[derive(Debug, Clone)]
 pub struct SymbolTable {
-    table: HashMap<String, u16>,
+    table: HashMap<String, Symbol>,
     next_variable_address: u16,
 }
 
@@ -18,16 +52,39 @@ impl SymbolTable {
     pub fn add_variable(&mut self, symbol: String) -> crate::Result<u16> {
         if let Entry::Vacant(_) = self.table.entry(symbol.clone()) {
             let address = self.next_variable_address;
-            self.table.insert(symbol, address);
+            self.table.insert(symbol, Symbol { address, kind: SymbolKind::Variable });
             self.next_variable_address += 1;
             Ok(address)
         } else {
-            Ok(*self.table.get(&symbol).ok_or_else(|| format!("Symbol {} not found", symbol))?)
+            Ok(self.table.get(&symbol).ok_or_else(|| format!("Symbol {} not found", symbol))?.address)
         }
     }
 
+    // The RAM address the next new variable will get.
+    pub fn next_variable_address(&self) -> u16 {
+        self.next_variable_address
+    }
+
     pub fn add_label(&mut self, symbol: String, symbol_address: u16) {
-        self.table.entry(symbol).or_insert(symbol_address);
+        self.table.entry(symbol).or_insert(Symbol {
+            address: symbol_address,
+            kind: SymbolKind::Label,
+        });
+    }
+
+    // Updates a label after the code before it has changed size.
+    pub fn move_label(&mut self, symbol: &str, symbol_address: u16) {
+        if let Some(entry) = self.table.get_mut(symbol).filter(|entry| entry.kind == SymbolKind::Label) {
+            entry.address = symbol_address;
+        }
+    }
+
+    // Constants defined with `.equ`. Their `address` is simply their value.
+    pub fn add_constant(&mut self, symbol: String, value: u16) {
+        self.table.entry(symbol).or_insert(Symbol {
+            address: value,
+            kind: SymbolKind::Constant,
+        });
     }
 
     pub fn contains(&self, symbol: &str) -> bool {
@@ -35,28 +92,100 @@ impl SymbolTable {
     }
 
     pub fn get_address(&self, symbol: &str) -> Option<&u16> {
+        self.table.get(symbol).map(|entry| &entry.address)
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&Symbol> {
         self.table.get(symbol)
     }
 
-    fn map_with_predefined_symbols() -> HashMap<String, u16> {
+    // Entries ordered by kind, then address, then name so output is stable.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Symbol)> {
+        let mut entries: Vec<(&str, &Symbol)> = self
+            .table
+            .iter()
+            .map(|(name, symbol)| (name.as_str(), symbol))
+            .collect();
+        entries.sort_by_key(|(name, symbol)| (symbol.kind, symbol.address, *name));
+        entries.into_iter()
+    }
+
+    pub fn write_sym<W: Write>(&self, mut writer: W) -> crate::Result<()> {
+        for (name, symbol) in self.iter() {
+            writeln!(writer, "{:>5}  {:<10}  {}", symbol.address, symbol.kind, name)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String {
+        let entries: Vec<String> = self
+            .iter()
+            .map(|(name, symbol)| {
+                format!(
+                    "    {{\"name\": {}, \"address\": {}, \"kind\": \"{}\"}}",
+                    json_string(name),
+                    symbol.address,
+                    symbol.kind
+                )
+            })
+            .collect();
+        format!("[\n{}\n]", entries.join(",\n"))
+    }
+
+    fn map_with_predefined_symbols() -> HashMap<String, Symbol> {
         let mut table = HashMap::new();
+        let mut insert = |symbol: String, address: u16| {
+            table.insert(symbol, Symbol { address, kind: SymbolKind::Predefined });
+        };
         let symbols = [("SP", 0), ("LCL", 1), ("ARG", 2), ("THIS", 3), ("THAT", 4)];
         for (symbol, address) in symbols.iter() {
-            table.insert(symbol.to_string(), *address);
+            insert(symbol.to_string(), *address);
         }
         for i in 0..16 {
-            table.insert(format!("R{}", i), i);
+            insert(format!("R{}", i), i);
         }
-        table.insert("SCREEN".to_string(), 16384);
-        table.insert("KBD".to_string(), 24576);
+        insert("SCREEN".to_string(), SCREEN);
+        insert("KBD".to_string(), KBD);
 
         table
     }
 }
 
-
 impl Default for SymbolTable {
     fn default() -> Self {
         SymbolTable::new()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_iter_kinds() {
+        let mut table = SymbolTable::new();
+        table.add_label("LOOP".to_string(), 4);
+        table.add_variable("i".to_string()).unwrap();
+
+        let entries: Vec<(&str, SymbolKind, u16)> = table
+            .iter()
+            .filter(|(_, symbol)| symbol.kind != SymbolKind::Predefined)
+            .map(|(name, symbol)| (name, symbol.kind, symbol.address))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![("LOOP", SymbolKind::Label, 4), ("i", SymbolKind::Variable, 16)]
+        );
+        assert_eq!(table.iter().count(), 25);
+    }
+
+    #[test]
+    fn test_json_escaping() {
+        let mut table = SymbolTable::new();
+        table.add_label("a\"b".to_string(), 1);
+        assert!(table
+            .to_json()
+            .contains(r#"{"name": "a\"b", "address": 1, "kind": "label"}"#));
+    }
+}
@@ -0,0 +1,463 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::error::ErrorCode;
+use crate::lexer::is_symbol;
+use crate::suggest::closest;
+use crate::AsmError;
+
+const COMMANDS: [&str; 17] = [
+    "add", "sub", "neg", "eq", "gt", "lt", "and", "or", "not", "push", "pop", "label", "goto", "if-goto", "function",
+    "call", "return",
+];
+const SEGMENTS: [&str; 8] = ["constant", "local", "argument", "this", "that", "temp", "pointer", "static"];
+
+// Pushes D onto the stack.
+const PUSH_D: &str = "@SP\nA=M\nM=D\n@SP\nM=M+1\n";
+// Pops the top of the stack into D.
+const POP_D: &str = "@SP\nAM=M-1\nD=M\n";
+
+// Translates VM code (Nand2Tetris projects 7 and 8) into Hack assembly that
+// uses the predefined `SP`, `LCL`, `ARG`, `THIS`, `THAT` and `R13`-`R15`
+// symbols. Feed it every .vm file of a program, then take the result with
+// `finish`.
+pub struct Translator {
+    output: String,
+    pub errors: Vec<AsmError>,
+    // File stem of the file being translated, which prefixes its statics.
+    name: String,
+    file: Option<PathBuf>,
+    // Function being translated, which scopes labels and return addresses.
+    function: Option<String>,
+    label_count: usize,
+    // Whether any file defines `Sys.init`, in which case the bootstrap calls it.
+    has_sys_init: bool,
+}
+
+impl Translator {
+    pub fn new() -> Self {
+        Translator {
+            output: String::new(),
+            errors: Vec::new(),
+            name: String::new(),
+            file: None,
+            function: None,
+            label_count: 0,
+            has_sys_init: false,
+        }
+    }
+
+    // Translates one .vm file. `name` is its file stem, such as `Main`.
+    pub fn translate(&mut self, name: &str, file: Option<&Path>, source: &str) {
+        self.name = name.to_string();
+        self.file = file.map(Path::to_path_buf);
+        self.function = None;
+        if !is_symbol(name) || name.contains('$') {
+            let error = AsmError::error(
+                ErrorCode::InvalidVmCommand,
+                0,
+                format!("`{}` can't be used as a file name for static variables", name),
+            )
+            .with_hint("file names must be valid VM names, such as `Main.vm`");
+            self.errors.push(self.annotate(error));
+            return;
+        }
+
+        for (index, line) in source.lines().enumerate() {
+            let code = line.find("//").map_or(line, |comment| &line[..comment]).trim();
+            if code.is_empty() {
+                continue;
+            }
+            if let Err(error) = self.command(code, index + 1) {
+                let error = error.with_source(line).shifted(line.len() - line.trim_start().len());
+                self.errors.push(self.annotate(error));
+            }
+        }
+    }
+
+    // The translated program, starting with the bootstrap code if there is a
+    // `Sys.init` to call.
+    pub fn finish(mut self) -> Result<String, Vec<AsmError>> {
+        if !self.errors.is_empty() {
+            return Err(self.errors);
+        }
+        if !self.has_sys_init {
+            return Ok(self.output);
+        }
+        let program = std::mem::take(&mut self.output);
+        self.function = Some("Sys".to_string());
+        self.emit("// bootstrap\n@256\nD=A\n@SP\nM=D\n");
+        self.call("Sys.init", 0);
+        self.output.push_str(&program);
+        Ok(self.output)
+    }
+
+    fn annotate(&self, error: AsmError) -> AsmError {
+        match &self.file {
+            Some(file) => error.with_file(file.clone()),
+            None => error,
+        }
+    }
+
+    fn emit(&mut self, code: &str) {
+        self.output.push_str(code);
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn command(&mut self, code: &str, line: usize) -> Result<(), AsmError> {
+        let words: Vec<(&str, Range<usize>)> = code
+            .split_whitespace()
+            .map(|word| {
+                let start = word.as_ptr() as usize - code.as_ptr() as usize;
+                (word, start..start + word.len())
+            })
+            .collect();
+        let error = |message: String, span: Range<usize>| {
+            AsmError::error(ErrorCode::InvalidVmCommand, line, message).with_span(span)
+        };
+        let (command, command_span) = words[0].clone();
+        let arity = match command {
+            "push" | "pop" | "function" | "call" => 2,
+            "label" | "goto" | "if-goto" => 1,
+            _ if COMMANDS.contains(&command) => 0,
+            _ => {
+                let error = error(format!("unknown VM command `{}`", command), command_span);
+                return Err(match closest(command, COMMANDS) {
+                    Some(suggestion) => error.with_hint(format!("did you mean `{}`?", suggestion)),
+                    None => error,
+                });
+            }
+        };
+        if words.len() != arity + 1 {
+            let span = words.get(arity + 1).map_or(0..code.len(), |(_, span)| span.start..code.len());
+            return Err(error(
+                format!("`{}` takes {} argument(s) but {} were given", command, arity, words.len() - 1),
+                span,
+            ));
+        }
+
+        self.emit(&format!("// {}\n", words.iter().map(|(word, _)| *word).collect::<Vec<_>>().join(" ")));
+        let index = |position: usize| -> Result<u16, AsmError> {
+            let (word, span) = words[position].clone();
+            word.parse::<u16>()
+                .ok()
+                .filter(|index| *index <= 0x7FFF)
+                .ok_or_else(|| error(format!("invalid number `{}`", word), span))
+        };
+        let symbol = |position: usize| -> Result<&str, AsmError> {
+            let (word, span) = words[position].clone();
+            match is_symbol(word) && !word.contains('$') {
+                true => Ok(word),
+                false => Err(error(format!("invalid name `{}`", word), span)
+                    .with_hint("names use letters, digits, `_`, `.` and `:`, and don't start with a digit")),
+            }
+        };
+        match command {
+            "add" => self.emit("@SP\nAM=M-1\nD=M\nA=A-1\nM=D+M\n"),
+            "sub" => self.emit("@SP\nAM=M-1\nD=M\nA=A-1\nM=M-D\n"),
+            "and" => self.emit("@SP\nAM=M-1\nD=M\nA=A-1\nM=D&M\n"),
+            "or" => self.emit("@SP\nAM=M-1\nD=M\nA=A-1\nM=D|M\n"),
+            "neg" => self.emit("@SP\nA=M-1\nM=-M\n"),
+            "not" => self.emit("@SP\nA=M-1\nM=!M\n"),
+            "eq" | "gt" | "lt" => {
+                let label = self.unique_label("cmp");
+                let jump = command.to_uppercase();
+                self.emit(&format!(
+                    "@SP\nAM=M-1\nD=M\nA=A-1\nD=M-D\nM=-1\n@{label}\nD;J{jump}\n@SP\nA=M-1\nM=0\n({label})\n"
+                ));
+            }
+            "push" | "pop" => {
+                let (segment, segment_span) = words[1].clone();
+                let index_value = index(2)?;
+                let code = self.memory_access(command == "push", segment, index_value).map_err(|message| {
+                    let span = match message.contains("index") {
+                        true => words[2].1.clone(),
+                        false => segment_span.clone(),
+                    };
+                    let error = error(message, span);
+                    match closest(segment, SEGMENTS) {
+                        Some(suggestion) if !SEGMENTS.contains(&segment) => {
+                            error.with_hint(format!("did you mean `{}`?", suggestion))
+                        }
+                        _ => error,
+                    }
+                })?;
+                self.emit(&code);
+            }
+            "label" => {
+                let label = self.scoped_label(symbol(1)?);
+                self.emit(&format!("({})\n", label));
+            }
+            "goto" => {
+                let label = self.scoped_label(symbol(1)?);
+                self.emit(&format!("@{}\n0;JMP\n", label));
+            }
+            "if-goto" => {
+                let label = self.scoped_label(symbol(1)?);
+                self.emit(&format!("{}@{}\nD;JNE\n", POP_D, label));
+            }
+            "function" => {
+                let name = symbol(1)?.to_string();
+                let locals = index(2)?;
+                if name == "Sys.init" {
+                    self.has_sys_init = true;
+                }
+                self.emit(&format!("({})\n", name));
+                for _ in 0..locals {
+                    self.emit(&format!("D=0\n{}", PUSH_D));
+                }
+                self.function = Some(name);
+            }
+            "call" => {
+                let name = symbol(1)?.to_string();
+                let arguments = index(2)?;
+                self.call(&name, arguments);
+            }
+            "return" => self.emit(concat!(
+                "@LCL\nD=M\n@R13\nM=D\n",
+                "@5\nA=D-A\nD=M\n@R14\nM=D\n",
+                "@SP\nAM=M-1\nD=M\n@ARG\nA=M\nM=D\n",
+                "@ARG\nD=M+1\n@SP\nM=D\n",
+                "@R13\nAM=M-1\nD=M\n@THAT\nM=D\n",
+                "@R13\nAM=M-1\nD=M\n@THIS\nM=D\n",
+                "@R13\nAM=M-1\nD=M\n@ARG\nM=D\n",
+                "@R13\nAM=M-1\nD=M\n@LCL\nM=D\n",
+                "@R14\nA=M\n0;JMP\n",
+            )),
+            _ => unreachable!("checked against COMMANDS"),
+        }
+        Ok(())
+    }
+
+    // The assembly for `push segment index` or `pop segment index`, or a
+    // message saying what is wrong with the segment or index.
+    fn memory_access(&self, push: bool, segment: &str, index: u16) -> Result<String, String> {
+        let fixed = |base: u16, size: u16| match index < size {
+            true => Ok(format!("R{}", base + index)),
+            false => Err(format!("`{}` index {} is out of range 0 to {}", segment, index, size - 1)),
+        };
+        let address = match segment {
+            "constant" if push => return Ok(format!("@{}\nD=A\n{}", index, PUSH_D)),
+            "constant" => return Err("can't pop to the `constant` segment".to_string()),
+            "local" | "argument" | "this" | "that" => {
+                let base = match segment {
+                    "local" => "LCL",
+                    "argument" => "ARG",
+                    "this" => "THIS",
+                    _ => "THAT",
+                };
+                return Ok(match (push, index) {
+                    (true, 0) => format!("@{}\nA=M\nD=M\n{}", base, PUSH_D),
+                    (true, _) => format!("@{}\nD=M\n@{}\nA=D+A\nD=M\n{}", base, index, PUSH_D),
+                    (false, 0) => format!("{}@{}\nA=M\nM=D\n", POP_D, base),
+                    (false, _) => format!(
+                        "@{}\nD=M\n@{}\nD=D+A\n@R13\nM=D\n{}@R13\nA=M\nM=D\n",
+                        base, index, POP_D
+                    ),
+                });
+            }
+            "temp" => fixed(5, 8)?,
+            "pointer" => match index {
+                0 => "THIS".to_string(),
+                1 => "THAT".to_string(),
+                _ => return Err(format!("`pointer` index {} is out of range 0 to 1", index)),
+            },
+            "static" => format!("{}.{}", self.name, index),
+            _ => return Err(format!("unknown segment `{}`", segment)),
+        };
+        Ok(match push {
+            true => format!("@{}\nD=M\n{}", address, PUSH_D),
+            false => format!("{}@{}\nM=D\n", POP_D, address),
+        })
+    }
+
+    // Saves the caller's frame, jumps to `name` and returns to just after.
+    fn call(&mut self, name: &str, arguments: u16) {
+        let return_label = self.unique_label("ret");
+        self.emit(&format!("@{}\nD=A\n{}", return_label, PUSH_D));
+        for pointer in ["LCL", "ARG", "THIS", "THAT"] {
+            self.emit(&format!("@{}\nD=M\n{}", pointer, PUSH_D));
+        }
+        self.emit(&format!(
+            "@SP\nD=M\n@{}\nD=D-A\n@ARG\nM=D\n@SP\nD=M\n@LCL\nM=D\n@{}\n0;JMP\n({})\n",
+            arguments + 5,
+            name,
+            return_label
+        ));
+    }
+
+    // `label` as written in the current function, `f$label`.
+    fn scoped_label(&self, label: &str) -> String {
+        format!("{}${}", self.function.as_deref().unwrap_or(&self.name), label)
+    }
+
+    // A label no VM code can clash with, such as `Main.main$$ret.3`: VM names
+    // can't contain `$`, so `scoped_label` never produces a `$$`.
+    fn unique_label(&mut self, kind: &str) -> String {
+        self.label_count += 1;
+        format!("{}$${}.{}", self.function.as_deref().unwrap_or(&self.name), kind, self.label_count)
+    }
+}
+
+impl Default for Translator {
+    fn default() -> Self {
+        Translator::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{assemble_str, Emulator, Options};
+
+    fn run(files: &[(&str, &str)], stack: &[(u16, u16)]) -> Emulator {
+        let mut translator = Translator::new();
+        for (name, source) in files {
+            translator.translate(name, None, source);
+        }
+        let asm = translator.finish().unwrap();
+        let program = assemble_str(&asm, Options::default()).unwrap();
+        let mut emulator = Emulator::new(program.words);
+        for (address, value) in stack {
+            emulator.poke(*address, *value);
+        }
+        emulator.run(100_000);
+        emulator
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let source = "\
+push constant 7
+push constant 8
+add
+push constant 3
+sub
+push constant 12
+eq
+push constant 5
+push constant 4
+gt
+push constant 5
+push constant 4
+lt
+push constant 6
+neg
+not
+";
+        let emulator = run(&[("Test", source)], &[(0, 256)]);
+        assert_eq!(emulator.peek(0), 260);
+        assert_eq!(&emulator.ram()[256..260], [0xFFFF, 0xFFFF, 0, 5]);
+    }
+
+    #[test]
+    fn test_segments() {
+        let source = "\
+push constant 10
+pop local 0
+push constant 21
+pop argument 2
+push constant 3030
+pop pointer 0
+push constant 36
+pop this 6
+push constant 5
+pop temp 6
+push constant 9
+pop static 1
+push local 0
+push argument 2
+add
+push this 6
+add
+push temp 6
+add
+push static 1
+add
+push pointer 0
+add
+";
+        let emulator = run(&[("Test", source)], &[(0, 256), (1, 300), (2, 400)]);
+        assert_eq!(emulator.peek(0), 257);
+        assert_eq!(emulator.peek(256), 10 + 21 + 36 + 5 + 9 + 3030);
+        assert_eq!(emulator.peek(402), 21);
+        assert_eq!(emulator.peek(3036), 36);
+        assert_eq!(emulator.peek(11), 5);
+    }
+
+    #[test]
+    fn test_functions() {
+        let sys = "\
+function Sys.init 0
+push constant 5
+call Main.factorial 1
+pop static 0
+label HALT
+goto HALT
+";
+        let main = "\
+// factorial(n) = n == 0 ? 1 : n * factorial(n - 1), multiplying by repeated addition
+function Main.factorial 2
+push argument 0
+if-goto RECURSE
+push constant 1
+return
+label RECURSE
+push argument 0
+push constant 1
+sub
+call Main.factorial 1
+pop local 0
+push constant 0
+pop local 1
+label LOOP
+push argument 0
+push constant 0
+eq
+if-goto DONE
+push local 1
+push local 0
+add
+pop local 1
+push argument 0
+push constant 1
+sub
+pop argument 0
+goto LOOP
+label DONE
+push local 1
+return
+";
+        let emulator = run(&[("Main", main), ("Sys", sys)], &[]);
+        assert_eq!(emulator.peek(16), 120);
+        // Sys.init's frame is still on the stack.
+        assert_eq!(emulator.peek(0), 261);
+
+        // Generated labels don't clash with VM labels of the same shape.
+        let mut translator = Translator::new();
+        translator.translate("Main", None, "function F 0\nlabel cmp.1\npush constant 1\npush constant 1\neq\nreturn\n");
+        let asm = translator.finish().unwrap();
+        assert!(asm.contains("(F$cmp.1)") && asm.contains("(F$$cmp.1)"));
+        assert!(assemble_str(&asm, Options::default()).is_ok());
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut translator = Translator::new();
+        translator.translate("Main", None, "pusj constant 1\npop constant 0\npush temp 8\nadd 1\nlabel 1abc\n");
+        let errors = translator.finish().unwrap_err();
+        let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            [
+                "unknown VM command `pusj`",
+                "can't pop to the `constant` segment",
+                "`temp` index 8 is out of range 0 to 7",
+                "`add` takes 0 argument(s) but 1 were given",
+                "invalid name `1abc`",
+            ]
+        );
+        assert_eq!(errors[0].hint.as_deref(), Some("did you mean `push`?"));
+        assert_eq!(errors[2].span, 10..11);
+    }
+}
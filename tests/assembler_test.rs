@@ -1,4 +1,11 @@
-use std::{env, error::Error, fs, path::PathBuf, process::Command};
+use std::{
+    env,
+    error::Error,
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
 
 #[test]
 fn test_asm() {
@@ -66,10 +73,189 @@ fn run_assembler(input: &str) -> Result<(), Box<dyn Error>> {
 
     if !output.status.success() {
         let errmsg = String::from_utf8_lossy(&output.stderr);
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            errmsg,
-        )));
+        return Err(Box::new(std::io::Error::other(errmsg)));
     }
     Ok(())
 }
+
+#[test]
+fn directory_input() {
+    let dir = env::temp_dir().join(format!("hack_assembler_dir_{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("Failed to create test directory");
+    fs::write(dir.join("Good.asm"), "@2\nD=A\n").expect("Failed to write input file");
+    fs::write(dir.join("Bad.asm"), "D=Q\n").expect("Failed to write input file");
+
+    let output = Command::new("cargo")
+        .args(["run", "--", "-f"])
+        .arg(&dir)
+        .output()
+        .expect("Failed to run assembler");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(!output.status.success(), "A failed file should fail the run");
+    assert!(stdout.contains("1 succeeded, 1 failed"), "Missing summary: {}", stdout);
+    assert_eq!(
+        fs::read_to_string(dir.join("Good.hack")).expect("Failed to read output file"),
+        "0000000000000010\n1110110000010000\n"
+    );
+    assert!(!dir.join("Bad.hack").exists());
+
+    // Clean up
+    fs::remove_dir_all(&dir).expect("Failed to clean up test directory");
+}
+
+#[test]
+fn stdin_to_stdout() {
+    let project_dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+    let input = fs::read_to_string(PathBuf::from(&project_dir).join("tests/input/test.asm"))
+        .expect("Failed to read input file");
+    let expected = fs::read_to_string(PathBuf::from(&project_dir).join("tests/expected/test.hack"))
+        .expect("Failed to read expected output file");
+
+    let mut child = Command::new("cargo")
+        .args(["run", "--", "-f", "-", "-o", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run assembler");
+    child
+        .stdin
+        .take()
+        .expect("Failed to open stdin")
+        .write_all(input.as_bytes())
+        .expect("Failed to write to stdin");
+    let output = child.wait_with_output().expect("Failed to wait for assembler");
+
+    assert!(output.status.success(), "Assembler failed to run");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        expected.replace("\r\n", "\n").trim(),
+        "Assembler output did not match expected output"
+    );
+}
+
+#[test]
+fn test_script() {
+    let dir = env::temp_dir().join(format!("hack_assembler_tst_{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("Failed to create test directory");
+    let mult = "@R2\nM=0\n(LOOP)\n@R0\nD=M\n@END\nD;JEQ\n@R1\nD=M\n@R2\nM=D+M\n@R0\nM=M-1\n@LOOP\n0;JMP\n(END)\n@END\n0;JMP\n";
+    fs::write(dir.join("Mult.asm"), mult).expect("Failed to write input file");
+    let script = "\
+load Mult.asm,
+output-file Mult.out,
+compare-to Mult.cmp,
+output-list RAM[0]%D2.6.2 RAM[1]%D2.6.2 RAM[2]%D2.6.2;
+
+set PC 0,
+set RAM[0] 3,
+set RAM[1] 5,
+set RAM[2] -1;   // The program must clear the product
+repeat 100 {
+  ticktock;
+}
+set RAM[0] 3,
+output;
+
+set PC 0,
+set RAM[0] 0,
+set RAM[1] 7;
+repeat 20 {
+  ticktock;
+}
+output;
+";
+    fs::write(dir.join("Mult.tst"), script).expect("Failed to write script");
+    let expected = "|  RAM[0]  |  RAM[1]  |  RAM[2]  |\n|       3  |       5  |      15  |\n|       0  |       7  |       0  |\n";
+    fs::write(dir.join("Mult.cmp"), expected).expect("Failed to write compare file");
+
+    let run = || {
+        Command::new("cargo")
+            .args(["run", "--", "test", "-f"])
+            .arg(dir.join("Mult.tst"))
+            .output()
+            .expect("Failed to run assembler")
+    };
+    let output = run();
+    assert!(output.status.success(), "Script failed: {}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Comparison ended successfully"));
+    assert_eq!(fs::read_to_string(dir.join("Mult.out")).expect("Failed to read output file"), expected);
+
+    fs::write(dir.join("Mult.cmp"), expected.replace("15", "16")).expect("Failed to write compare file");
+    let output = run();
+    assert!(!output.status.success(), "A mismatch should fail the run");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Comparison failure at line 2"));
+
+    // Clean up
+    fs::remove_dir_all(&dir).expect("Failed to clean up test directory");
+}
+
+#[test]
+fn debug_positional_input() {
+    let project_dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+    let input = PathBuf::from(&project_dir).join("tests/input/test.asm");
+
+    let mut child = Command::new("cargo")
+        .args(["run", "--", "debug"])
+        .arg(&input)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run debugger");
+    child
+        .stdin
+        .take()
+        .expect("Failed to open stdin")
+        .write_all(b"step\nquit\n")
+        .expect("Failed to write to stdin");
+    let output = child.wait_with_output().expect("Failed to wait for debugger");
+
+    assert!(output.status.success(), "Debugger failed to run");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("Debugging {}", input.display())), "Unexpected output: {}", stdout);
+}
+
+#[test]
+fn language_server_over_stdio() {
+    let messages = [
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"capabilities":{}}}"#,
+        r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"untitled:Prog.asm","languageId":"hack","version":1,"text":"(LOOP)\n@LOOP\n0;JMQ\n"}}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/hover","params":{"textDocument":{"uri":"untitled:Prog.asm"},"position":{"line":1,"character":2}}}"#,
+        r#"{"jsonrpc":"2.0","id":3,"method":"shutdown"}"#,
+        r#"{"jsonrpc":"2.0","method":"exit"}"#,
+    ];
+    let input: String = messages
+        .iter()
+        .map(|message| format!("Content-Length: {}\r\n\r\n{}", message.len(), message))
+        .collect();
+
+    let mut child = Command::new("cargo")
+        .args(["run", "--bin", "hack_lsp"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run the language server");
+    child
+        .stdin
+        .take()
+        .expect("Failed to open stdin")
+        .write_all(input.as_bytes())
+        .expect("Failed to write to stdin");
+    let output = child.wait_with_output().expect("Failed to wait for the language server");
+    assert!(output.status.success(), "Language server didn't exit cleanly");
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let bodies: Vec<&str> = stdout
+        .split("Content-Length: ")
+        .skip(1)
+        .map(|message| message.split_once("\r\n\r\n").unwrap().1)
+        .collect();
+    assert_eq!(bodies.len(), 4);
+    assert!(bodies[0].starts_with(r#"{"jsonrpc":"2.0","id":1,"result":{"capabilities":"#));
+    assert!(bodies[1].contains(r#""method":"textDocument/publishDiagnostics""#));
+    assert!(bodies[1].contains(
+        r#""range":{"start":{"line":2,"character":2},"end":{"line":2,"character":5}},"severity":1,"code":"E0004""#
+    ));
+    assert!(bodies[2].contains(r#"`LOOP`: label at ROM[0]"#));
+    assert_eq!(bodies[3], r#"{"jsonrpc":"2.0","id":3,"result":null}"#);
+}
@@ -0,0 +1,119 @@
+use hack_assembler::{assemble_str, format_str, Emulator, ErrorCode, Halt, Options, Severity, SymbolKind};
+
+#[test]
+fn assemble_from_string() {
+    let source = include_str!("input/test.asm");
+    let expected: Vec<u16> = include_str!("expected/test.hack")
+        .lines()
+        .map(|line| u16::from_str_radix(line.trim(), 2).expect("Invalid expected word"))
+        .collect();
+
+    let program = assemble_str(source, Options::default()).expect("Assembly failed");
+
+    assert_eq!(program.words, expected);
+    assert!(program.warnings.is_empty());
+    let loop_label = program.symbol_table.get("LOOP").expect("Missing LOOP label");
+    assert_eq!(loop_label.address, 4);
+    assert_eq!(loop_label.kind, SymbolKind::Label);
+    assert_eq!(program.symbol_table.get_address("i"), Some(&16));
+}
+
+#[test]
+fn diagnostics_from_string() {
+    let options = Options {
+        warn_noncanonical: true,
+        ..Options::default()
+    };
+    let diagnostics = assemble_str("DM=1\nD=Q\n", options).expect_err("Assembly should fail");
+
+    assert_eq!(diagnostics.errors.len(), 1);
+    assert_eq!(diagnostics.errors[0].code, ErrorCode::InvalidComp);
+    assert_eq!(diagnostics.errors[0].line, 2);
+    assert_eq!(diagnostics.warnings.len(), 1);
+    assert_eq!(diagnostics.warnings[0].severity, Severity::Warning);
+}
+
+#[test]
+fn macro_expansion() {
+    let source = ".macro SET addr, value\n@\\value\nD=A\n@\\addr\nM=D\n.endm\nSET R1, 5\n";
+    let program = assemble_str(source, Options::default()).expect("Assembly failed");
+
+    assert_eq!(program.words, [5, 0b1110110000010000, 1, 0b1110001100001000]);
+}
+
+#[test]
+fn includes_need_a_source_file() {
+    let diagnostics = assemble_str(".include \"/etc/hosts\"\n", Options::default()).expect_err("Assembly should fail");
+
+    assert_eq!(diagnostics.errors.len(), 1);
+    assert_eq!(diagnostics.errors[0].code, ErrorCode::IncludeNotFound);
+    assert_eq!(diagnostics.errors[0].message, "can't include `/etc/hosts` without a source file");
+}
+
+#[test]
+fn expressions_with_forward_references() {
+    let source = "@END-1\n.equ ROWS 16\n@(ROWS*32)\n@SCREEN+0x20\n(END)\n";
+    let program = assemble_str(source, Options::default()).expect("Assembly failed");
+
+    assert_eq!(program.words, [2, 512, 16416]);
+}
+
+#[test]
+fn formatting_preserves_the_program() {
+    for source in [include_str!("input/test.asm"), include_str!("input/Pong.asm")] {
+        let formatted = format_str(source, 4);
+        let original = assemble_str(source, Options::default()).expect("Assembly failed");
+        let reformatted = assemble_str(&formatted, Options::default()).expect("Assembly failed");
+
+        assert_eq!(original.words, reformatted.words);
+        assert_eq!(format_str(&formatted, 4), formatted);
+    }
+}
+
+#[test]
+fn optimized_program_computes_the_same() {
+    // Sums 1..=10 into `sum`, with the kind of redundancy generated code has.
+    let source = "\
+@10
+D=A
+@n
+M=D
+@sum
+M=0
+(LOOP)
+@n
+D=M
+@DONE
+D;JEQ
+@n
+D=M
+@sum
+M=D+M
+@n
+M=M-1
+D=D
+@LOOP
+0;JMP
+@sum
+(DONE)
+@DONE
+0;JMP
+";
+    let plain = assemble_str(source, Options::default()).expect("Assembly failed");
+    let optimize = Options {
+        optimize: true,
+        ..Options::default()
+    };
+    let optimized = assemble_str(source, optimize).expect("Assembly failed");
+    assert_eq!(optimized.words.len(), plain.words.len() - 2);
+
+    let sums: Vec<u16> = [plain.words, optimized.words]
+        .into_iter()
+        .map(|words| {
+            let mut emulator = Emulator::new(words);
+            assert!(matches!(emulator.run(10_000), Halt::Loop { .. }));
+            emulator.peek(17)
+        })
+        .collect();
+    assert_eq!(sums, [55, 55]);
+}